@@ -20,6 +20,9 @@ cd -
 
 # Copy the needed files into an ISO image.
 mkdir -p target/iso_root
+
+# Extract the demangled function symbols, sorted by address, so backtraces can be symbolized.
+nm -C -n --defined-only $KERNEL | sed -n 's/^\([0-9a-f]*\) [tTwW] /\1 /p' > target/iso_root/kernel.sym
 cp $KERNEL conf/limine.cfg target/limine/limine.sys target/limine/limine-cd.bin \
 target/limine/limine-cd-efi.bin target/iso_root
 
@@ -35,7 +38,7 @@ target/limine/limine-deploy "$KERNEL".iso
 
 # Run the created image with QEMU.
 qemu-system-x86_64 \
-    -machine q35 -cpu qemu64 -smp 1 -M smm=off\
+    -machine q35 -cpu qemu64 -smp 4 -M smm=off\
     -D target/log.txt -d int,guest_errors\
     -serial stdio \
     -netdev user,id=pizza -device e1000,netdev=pizza,id=ck_nic0 -object filter-dump,id=pizza,netdev=pizza,file=qemulog.log \
@@ -7,35 +7,28 @@ use alloc::{
 };
 
 use crate::{
-    io::port::PortReadWrite, paging::mapper::convert_to_virtual_raw, pci::get_pci, serial_println,
+    apic::ioapic::{Polarity, TriggerMode},
+    io::port::PortReadWrite,
+    paging::mapper::convert_to_virtual_raw,
+    pci::get_pci,
+    serial_println,
     utils::sleep_ms,
 };
 
-use super::{get_acpi, AcpiTableKind};
+use super::get_acpi;
+
+/// ACPI IRQ resource flags.
+const ACPI_IRQ_EDGE_TRIGGERED: u8 = 1 << 0;
+const ACPI_IRQ_ACTIVE_LOW: u8 = 1 << 3;
+
 struct LaiHost;
 
 impl lai::Host for LaiHost {
     fn scan(&self, _signature: &str, _index: usize) -> *const u8 {
-        if _signature == "DSDT" {
-            let fadt = get_acpi()
-                .rsdt
-                .iter()
-                .find_map(|kind| {
-                    if let AcpiTableKind::Fadt(fadt) = kind {
-                        return Some(fadt);
-                    }
-                    None
-                })
-                .unwrap();
-            convert_to_virtual_raw(fadt.dsdt.into()).as_ptr::<u8>()
-        } else {
-            get_acpi()
-                .rsdt
-                .raw_iter()
-                .find(|e| e.0.signature == _signature.as_bytes())
-                .map(|e| e.0 as *const _ as *const u8)
-                .unwrap_or(ptr::null_mut::<u8>())
-        }
+        get_acpi()
+            .find_table(_signature.as_bytes(), _index)
+            .map(|header| header as *const _ as *const u8)
+            .unwrap_or(ptr::null_mut::<u8>())
     }
 
     fn sleep(&self, ms: u64) {
@@ -95,3 +88,26 @@ pub fn init_lai() {
     lai::create_namespace();
     lai::enable_acpi(1);
 }
+
+/// Resolves the global system interrupt a PCI interrupt pin is routed to.
+pub fn route_pci_pin(
+    bus: u8,
+    slot: u8,
+    function: u8,
+    pin: u8,
+) -> Result<(u32, TriggerMode, Polarity), ()> {
+    let resource = lai::pci_route_pin(0, bus, slot, function, pin).map_err(|_| ())?;
+
+    let trigger_mode = if resource.irq_flags & ACPI_IRQ_EDGE_TRIGGERED != 0 {
+        TriggerMode::Edge
+    } else {
+        TriggerMode::Level
+    };
+    let polarity = if resource.irq_flags & ACPI_IRQ_ACTIVE_LOW != 0 {
+        Polarity::ActiveLow
+    } else {
+        Polarity::ActiveHigh
+    };
+
+    Ok((resource.base as u32, trigger_mode, polarity))
+}
@@ -1,9 +1,12 @@
-use core::{marker::PhantomData, mem, panic, str::from_utf8_unchecked};
+use core::{alloc::Layout, marker::PhantomData, mem, panic, ptr, str::from_utf8_unchecked};
 
-use alloc::{slice, string::String};
+use alloc::{slice, string::String, vec::Vec};
 use spin::Once;
 
-use crate::sync::spinlock::{SpinLock, SpinLockGuard};
+use crate::{
+    paging::mapper::convert_to_virtual_raw,
+    sync::spinlock::{SpinLock, SpinLockGuard},
+};
 
 use self::{fadt::Fadt, madt::Madt, rsdp::Rsdp, rsdt::Rsdt};
 
@@ -21,7 +24,30 @@ pub fn get_acpi<'a>() -> SpinLockGuard<'a, Acpi<'static>> {
 
 /// ACPI
 pub struct Acpi<'a> {
+    /// A copy of the RSDT, its table pointers still point to the firmware tables which are gone
+    /// once the boot memory is reclaimed. Use [`Acpi::tables`] instead.
     pub rsdt: &'a Rsdt,
+
+    /// Copies of all ACPI tables, including the DSDT.
+    tables: Vec<&'a AcpiHeader>,
+}
+
+impl<'a> Acpi<'a> {
+    /// Returns an iterator over the ACPI tables.
+    pub fn tables(&self) -> impl Iterator<Item = AcpiTableKind<'a>> + '_ {
+        self.tables.iter().map(|header| unsafe {
+            AcpiTableKind::try_parse(header).unwrap_or(AcpiTableKind::Unknown(header))
+        })
+    }
+
+    /// Finds the nth ACPI table with the provided signature.
+    pub fn find_table(&self, signature: &[u8], index: usize) -> Option<&'a AcpiHeader> {
+        self.tables
+            .iter()
+            .filter(|header| header.signature == signature)
+            .nth(index)
+            .copied()
+    }
 }
 
 pub unsafe fn init_acpi(rsdp_address: *const u8) {
@@ -30,10 +56,42 @@ pub unsafe fn init_acpi(rsdp_address: *const u8) {
     // Get rsdt.
     let rsdt = Rsdt::from_addr(rsdp.rsdt_address);
 
-    let acpi = Acpi { rsdt };
+    // The firmware tables may live in reclaimable memory, so the kernel only keeps copies.
+    let mut tables: Vec<&AcpiHeader> = rsdt
+        .raw_iter()
+        .map(|(header, _)| copy_table(header))
+        .collect();
+
+    let dsdt_address = tables.iter().find_map(|header| {
+        if let Ok(AcpiTableKind::Fadt(fadt)) = AcpiTableKind::try_parse(header) {
+            return Some(fadt.dsdt);
+        }
+        None
+    });
+    if let Some(dsdt_address) = dsdt_address {
+        let dsdt = &*convert_to_virtual_raw(dsdt_address.into()).as_ptr::<AcpiHeader>();
+        tables.push(copy_table(dsdt));
+    }
+
+    let rsdt = Rsdt::from_addr(copy_table(&rsdt.header) as *const AcpiHeader as *const u8);
+
+    let acpi = Acpi { rsdt, tables };
     ACPI.call_once(|| SpinLock::new(acpi));
 }
 
+/// Copies an ACPI table to the heap.
+unsafe fn copy_table(header: &AcpiHeader) -> &'static AcpiHeader {
+    let length = header.length as usize;
+    let copy = alloc::alloc::alloc(Layout::from_size_align(length, 8).unwrap());
+    assert!(
+        !copy.is_null(),
+        "Could not allocate a copy of an ACPI table"
+    );
+
+    ptr::copy_nonoverlapping(header as *const AcpiHeader as *const u8, copy, length);
+    &*(copy as *const AcpiHeader)
+}
+
 #[repr(C, packed)]
 #[derive(Debug)]
 /// Acpi Header
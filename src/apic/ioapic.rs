@@ -0,0 +1,158 @@
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::{
+    acpi::{madt::MadtEntryKind, Acpi, AcpiTableKind},
+    paging::mapper::{ioremap, IoMapping},
+    sync::spinlock::{IrqSpinLock, IrqSpinLockGuard},
+};
+
+/// I/O APIC Register Select
+const IOREGSEL: usize = 0x00;
+
+/// I/O APIC Register Window
+const IOWIN: usize = 0x10;
+
+/// I/O APIC Version Register
+const IOAPICVER: u32 = 0x01;
+
+/// I/O APIC Redirection Table, two registers per entry
+const IOREDTBL: u32 = 0x10;
+
+/// The size of the I/O APIC register space.
+const IOAPIC_REGISTERS_SIZE: u64 = 0x20;
+
+/// Redirection entry bits.
+const IOAPIC_ACTIVE_LOW: u64 = 1 << 13;
+const IOAPIC_LEVEL_TRIGGERED: u64 = 1 << 15;
+const IOAPIC_MASKED: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+pub struct IoApic {
+    registers: IoMapping,
+    /// The first global system interrupt handled by this I/O APIC.
+    gsi_base: u32,
+    /// The amount of redirection entries, and thus interrupt inputs.
+    entry_count: u32,
+}
+
+static IO_APICS: Once<IrqSpinLock<Vec<IoApic>>> = Once::new();
+
+pub fn get_io_apics<'a>() -> IrqSpinLockGuard<'a, Vec<IoApic>> {
+    IO_APICS.get().unwrap().lock()
+}
+
+pub unsafe fn init_io_apics(acpi: &Acpi) {
+    let mut io_apics = Vec::new();
+
+    for table in acpi.tables() {
+        if let AcpiTableKind::Madt(madt) = table {
+            for madt_entry in madt.iter() {
+                if let MadtEntryKind::IoApic(entry) = madt_entry {
+                    let address = PhysAddr::new(entry.apic_addr as u64);
+                    io_apics.push(IoApic::new(address, entry.global_system_interrupt_base));
+                }
+            }
+        }
+    }
+
+    IO_APICS.call_once(|| IrqSpinLock::new(io_apics));
+}
+
+/// Routes a global system interrupt to a vector on the CPU with the provided local APIC ID.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    apic_id: u8,
+    trigger_mode: TriggerMode,
+    polarity: Polarity,
+) -> Result<(), ()> {
+    let mut io_apics = get_io_apics();
+    let io_apic = io_apics
+        .iter_mut()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(())?;
+
+    let mut entry = vector as u64 | (apic_id as u64) << 56;
+    if trigger_mode == TriggerMode::Level {
+        entry |= IOAPIC_LEVEL_TRIGGERED;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= IOAPIC_ACTIVE_LOW;
+    }
+
+    unsafe { io_apic.set_redirection_entry(gsi, entry) };
+    Ok(())
+}
+
+/// Masks a global system interrupt.
+pub fn mask_gsi(gsi: u32) -> Result<(), ()> {
+    let mut io_apics = get_io_apics();
+    let io_apic = io_apics
+        .iter_mut()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(())?;
+
+    unsafe { io_apic.set_redirection_entry(gsi, IOAPIC_MASKED) };
+    Ok(())
+}
+
+impl IoApic {
+    unsafe fn new(address: PhysAddr, gsi_base: u32) -> IoApic {
+        let registers =
+            ioremap(address, IOAPIC_REGISTERS_SIZE).expect("Could not map the I/O APIC");
+
+        let mut io_apic = IoApic {
+            registers,
+            gsi_base,
+            entry_count: 0,
+        };
+        // Bits 16-23 hold the index of the last redirection entry.
+        io_apic.entry_count = ((io_apic.read_register(IOAPICVER) >> 16) & 0xff) + 1;
+
+        // Nothing is routed until a driver asks for it.
+        for index in 0..io_apic.entry_count {
+            io_apic.write_redirection_entry(index, IOAPIC_MASKED);
+        }
+
+        io_apic
+    }
+
+    unsafe fn read_register(&mut self, register: u32) -> u32 {
+        self.registers.write(IOREGSEL, register);
+        self.registers.read(IOWIN)
+    }
+
+    unsafe fn write_register(&mut self, register: u32, value: u32) {
+        self.registers.write(IOREGSEL, register);
+        self.registers.write(IOWIN, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entry_count
+    }
+
+    unsafe fn set_redirection_entry(&mut self, gsi: u32, entry: u64) {
+        self.write_redirection_entry(gsi - self.gsi_base, entry);
+    }
+
+    unsafe fn write_redirection_entry(&mut self, index: u32, entry: u64) {
+        let register = IOREDTBL + index * 2;
+        // Mask the entry while it is half written.
+        self.write_register(register, IOAPIC_MASKED as u32);
+        self.write_register(register + 1, (entry >> 32) as u32);
+        self.write_register(register, entry as u32);
+    }
+}
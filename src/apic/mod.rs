@@ -1,18 +1,19 @@
-use core::{cell::UnsafeCell, ops::Add};
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use alloc::vec::Vec;
 use spin::Once;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::PhysAddr;
 
 use crate::{
-    acpi::{
-        madt::{LocalApicEntry, MadtEntryKind},
-        Acpi, AcpiTableKind,
-    },
-    paging::mapper::convert_to_virtual,
-    sync::spinlock::{SpinLock, SpinLockGuard},
+    acpi::{Acpi, AcpiTableKind},
+    arch::{interrupt::SPURIOUS_VECTOR, percpu::this_cpu},
+    paging::mapper::{ioremap, IoMapping},
+    utils::sleep_ms,
 };
 
+use self::ioapic::init_io_apics;
+
+pub mod ioapic;
+
 // MSR apic base Register
 const IA32_APIC_BASE_MSR: u32 = 0x1B;
 
@@ -91,67 +92,126 @@ const LAPIC_TCCR: usize = 0x0390;
 /// Local APIC Divide Configuration Register (for Timer)
 const LAPIC_TDCR: usize = 0x03e0;
 
+/// Divide Configuration Register value for dividing the bus clock by 16.
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// LVT bits.
+const LAPIC_LVT_MASKED: u32 = 1 << 16;
+const LAPIC_LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// How long the local APIC timer is measured against the TSC.
+const TIMER_CALIBRATION_MS: u64 = 10;
+
+/// The size of the local APIC register space.
+const LAPIC_REGISTERS_SIZE: u64 = 0x400;
+
+/// The local APIC of a CPU, reached through [`PerCpu::apic`](crate::arch::percpu::PerCpu::apic).
+///
+/// Every CPU sees its own local APIC at the same physical address, so each maps it separately.
 pub struct Apic {
-    local_apic_address: VirtAddr,
+    registers: IoMapping,
 }
 
-// TODO: this needs doesn't need to be inside a spinlock as it is local to the core.
-// In the future, when we support multiprocessing, we can perhaps impl a core local struct that contains the local apic too.
-static APIC: Once<SpinLock<Apic>> = Once::new();
+/// The physical address of the local APIC registers, from the MADT.
+static LAPIC_ADDRESS: Once<PhysAddr> = Once::new();
 
-pub fn get_apic<'a>() -> SpinLockGuard<'a, Apic> {
-    APIC.get().unwrap().lock()
-}
+/// The address of the local APIC EOI register.
+///
+/// Interrupt handlers acknowledge interrupts through this instead of the per-CPU data. Every CPU
+/// reaches its own local APIC through it.
+static LAPIC_EOI_ADDR: AtomicU64 = AtomicU64::new(0);
+
+/// The local APIC timer ticks per millisecond, measured on the first CPU that starts its timer.
+static TIMER_TICKS_PER_MS: Once<u64> = Once::new();
 
 pub unsafe fn init_apic(acpi: &Acpi) {
-    let mut local_apics: Vec<&'static LocalApicEntry> = Vec::new();
     let mut local_apic_address: u32 = 0;
 
-    for table in acpi.rsdt.iter() {
+    for table in acpi.tables() {
         if let AcpiTableKind::Madt(madt) = table {
             local_apic_address = madt.apic_addr;
-            for madt_entry in madt.iter() {
-                if let MadtEntryKind::LocalApic(local_apic) = madt_entry {
-                    local_apics.push(local_apic)
-                }
-            }
         }
     }
 
-    APIC.call_once(|| {
-        let physical_addr = PhysAddr::new(local_apic_address.into());
-        let virtual_apic_addr = convert_to_virtual(physical_addr);
+    LAPIC_ADDRESS.call_once(|| PhysAddr::new(local_apic_address.into()));
+    init_local_apic();
 
-        let mut inner = Apic {
-            local_apic_address: virtual_apic_addr,
-        };
+    init_io_apics(acpi);
+}
 
-        inner.enable_local_apic();
+/// Maps and enables the local APIC of the current CPU.
+pub unsafe fn init_local_apic() {
+    let physical_addr = *LAPIC_ADDRESS.get().unwrap();
+    let registers =
+        ioremap(physical_addr, LAPIC_REGISTERS_SIZE).expect("Could not map the local APIC");
+
+    // Any CPU's mapping will do.
+    _ = LAPIC_EOI_ADDR.compare_exchange(
+        0,
+        registers.addr().as_u64() + LAPIC_EOI as u64,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    );
+
+    let apic = Apic { registers };
+    apic.enable_local_apic();
+    this_cpu().set_apic(apic);
+}
 
-        SpinLock::new(inner)
-    });
+/// Signals the end of the interrupt being handled to the local APIC.
+pub fn end_of_interrupt() {
+    let addr = LAPIC_EOI_ADDR.load(Ordering::Acquire);
+    if addr != 0 {
+        unsafe { (addr as *mut u32).write_volatile(0) };
+    }
 }
 
 impl Apic {
-    pub unsafe fn write_register(&mut self, offset: usize, value: u32) {
-        self.local_apic_address
-            .add(offset as u64)
-            .as_mut_ptr::<u32>()
-            .write_volatile(value);
+    pub unsafe fn write_register(&self, offset: usize, value: u32) {
+        self.registers.write(offset, value);
     }
     pub unsafe fn read_register(&self, offset: usize) -> u32 {
-        self.local_apic_address
-            .add(offset as u64)
-            .as_mut_ptr::<u32>()
-            .read_volatile()
+        self.registers.read(offset)
+    }
+
+    /// Returns the local APIC ID of the current CPU.
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read_register(LAPIC_ID) >> 24) as u8 }
     }
 
     // Enable local apic
-    pub unsafe fn enable_local_apic(&mut self) {
+    pub unsafe fn enable_local_apic(&self) {
         // Clear Task priority register.
         self.write_register(LAPIC_TPR, 0);
 
         // Configure Spurious Interrupt Vector Register
-        self.write_register(LAPIC_SVR, 0x100 | 0xff);
+        self.write_register(LAPIC_SVR, 0x100 | SPURIOUS_VECTOR as u32);
+    }
+
+    /// Raises `vector` `frequency` times a second on this CPU.
+    pub fn start_timer(&self, vector: u8, frequency: u32) {
+        let ticks_per_ms = *TIMER_TICKS_PER_MS.call_once(|| self.measure_timer());
+        let initial_count = (ticks_per_ms * 1000 / frequency as u64).clamp(1, u32::MAX as u64);
+
+        unsafe {
+            self.write_register(LAPIC_TDCR, LAPIC_TIMER_DIVIDE_BY_16);
+            self.write_register(LAPIC_TIMER, LAPIC_LVT_TIMER_PERIODIC | vector as u32);
+            self.write_register(LAPIC_TICR, initial_count as u32);
+        }
+    }
+
+    /// Counts the timer ticks in a millisecond, the timer keeps counting while it is masked.
+    fn measure_timer(&self) -> u64 {
+        unsafe {
+            self.write_register(LAPIC_TDCR, LAPIC_TIMER_DIVIDE_BY_16);
+            self.write_register(LAPIC_TIMER, LAPIC_LVT_MASKED);
+            self.write_register(LAPIC_TICR, u32::MAX);
+
+            sleep_ms(TIMER_CALIBRATION_MS);
+
+            let elapsed = u32::MAX - self.read_register(LAPIC_TCCR);
+            self.write_register(LAPIC_TICR, 0);
+            elapsed as u64 / TIMER_CALIBRATION_MS
+        }
     }
 }
@@ -0,0 +1,64 @@
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+use crate::{paging::mapper::is_mapped, serial_println};
+
+use super::symbols::Symbolized;
+
+/// Stops runaway walks through corrupted frame chains.
+const MAX_FRAMES: usize = 64;
+
+/// Prints the return addresses of the current call chain.
+#[inline(always)]
+pub fn print_backtrace() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    print_backtrace_from(None, rbp);
+}
+
+/// Prints `rip`, if provided, and the return addresses of the call chain whose innermost frame
+/// `rbp` points to.
+///
+/// This relies on frame pointers, which the build forces for the whole kernel. Every frame is
+/// checked to be mapped before it is read, so a corrupted chain ends the walk instead of faulting.
+pub fn print_backtrace_from(rip: Option<u64>, mut rbp: u64) {
+    serial_println!("Backtrace:");
+
+    let mut index = 0;
+    if let Some(rip) = rip {
+        serial_println!("  {:2}: {}", index, Symbolized(rip));
+        index += 1;
+    }
+
+    while index < MAX_FRAMES {
+        // The kernel stack starts with a null frame pointer.
+        if rbp == 0 || rbp % 8 != 0 {
+            break;
+        }
+        let Ok(frame) = VirtAddr::try_new(rbp) else {
+            break;
+        };
+        if !is_mapped(frame) || !is_mapped(frame + 15u64) {
+            serial_println!("  <unmapped frame at {:#018x}>", rbp);
+            break;
+        }
+
+        // Every frame starts with the caller's frame pointer, followed by the return address.
+        let (next_rbp, return_address) = unsafe {
+            let frame = frame.as_ptr::<u64>();
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            break;
+        }
+
+        serial_println!("  {:2}: {}", index, Symbolized(return_address));
+        index += 1;
+
+        if next_rbp == rbp {
+            break;
+        }
+        rbp = next_rbp;
+    }
+}
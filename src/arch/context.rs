@@ -0,0 +1,59 @@
+use core::arch::global_asm;
+
+use x86_64::VirtAddr;
+
+use crate::task::thread_start;
+
+// The caller saved registers are saved by the compiler around the call, so only the callee saved
+// ones have to be switched.
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global thread_trampoline
+// New threads start here, with a zeroed frame pointer ending their backtraces.
+thread_trampoline:
+    mov rdi, r12
+    call {start}
+    ud2
+"#,
+    start = sym thread_start,
+);
+
+extern "C" {
+    /// Saves the callee saved registers and the stack pointer into `*old_rsp`, and resumes the
+    /// thread whose stack pointer is `new_rsp`.
+    ///
+    /// Interrupts have to be disabled.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+/// Prepares a fresh stack so that switching to it calls [`thread_start`] with `arg`, returns the
+/// stack pointer to switch to.
+pub unsafe fn init_thread_stack(stack_top: VirtAddr, arg: u64) -> u64 {
+    // The registers `switch_context` pops, followed by the address it returns to. `arg` goes in
+    // r12, where the trampoline picks it up.
+    let frame = [0, 0, 0, arg, 0, 0, thread_trampoline as usize as u64];
+
+    // The trampoline calls `thread_start` with the stack 16 byte aligned.
+    let rsp = stack_top.as_u64() - 16 - core::mem::size_of_val(&frame) as u64;
+    (rsp as *mut [u64; 7]).write(frame);
+    rsp
+}
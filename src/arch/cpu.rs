@@ -0,0 +1,416 @@
+use core::{
+    arch::x86_64::{CpuidResult, __cpuid_count, _rdtsc},
+    str,
+};
+
+use spin::Once;
+
+use crate::io::port::PortReadWrite;
+
+/// The frequency of the PIT oscillator in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// PIT channel 2 data port.
+const PIT_CHANNEL_2: u16 = 0x42;
+/// PIT mode/command register.
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2 gate and output control.
+const PIT_CHANNEL_2_CONTROL: u16 = 0x61;
+/// How long the TSC is measured against the PIT when no CPUID leaf reports its frequency.
+const CALIBRATION_MS: u64 = 10;
+
+/// The maximum amount of cache levels and kinds recorded.
+const MAX_CACHES: usize = 8;
+
+/// Executes CPUID with the provided leaf and subleaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // Every x86_64 CPU supports CPUID.
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other,
+}
+
+/// The CPUID register holding a feature flag.
+#[derive(Debug, Clone, Copy)]
+enum FeatureRegister {
+    /// CPUID.01H:ECX
+    Leaf1Ecx,
+    /// CPUID.01H:EDX
+    Leaf1Edx,
+    /// CPUID.(EAX=07H,ECX=0):EBX
+    Leaf7Ebx,
+    /// CPUID.(EAX=07H,ECX=0):ECX
+    Leaf7Ecx,
+    /// CPUID.80000001H:ECX
+    ExtendedEcx,
+    /// CPUID.80000001H:EDX
+    ExtendedEdx,
+    /// CPUID.80000007H:EDX
+    PowerManagementEdx,
+}
+
+/// CPU features other subsystems pick code paths on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Sse3,
+    Pcid,
+    X2Apic,
+    TscDeadline,
+    Xsave,
+    Avx,
+    Rdrand,
+    Hypervisor,
+    Tsc,
+    Msr,
+    Apic,
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    FsGsBase,
+    Smep,
+    Invpcid,
+    Avx512F,
+    Smap,
+    Umip,
+    NoExecute,
+    Page1GiB,
+    Rdtscp,
+    InvariantTsc,
+}
+
+impl Feature {
+    /// Returns where the flag of the feature is found.
+    fn location(self) -> (FeatureRegister, u32) {
+        use FeatureRegister::*;
+
+        match self {
+            Feature::Sse3 => (Leaf1Ecx, 0),
+            Feature::Pcid => (Leaf1Ecx, 17),
+            Feature::X2Apic => (Leaf1Ecx, 21),
+            Feature::TscDeadline => (Leaf1Ecx, 24),
+            Feature::Xsave => (Leaf1Ecx, 26),
+            Feature::Avx => (Leaf1Ecx, 28),
+            Feature::Rdrand => (Leaf1Ecx, 30),
+            Feature::Hypervisor => (Leaf1Ecx, 31),
+            Feature::Tsc => (Leaf1Edx, 4),
+            Feature::Msr => (Leaf1Edx, 5),
+            Feature::Apic => (Leaf1Edx, 9),
+            Feature::Pat => (Leaf1Edx, 16),
+            Feature::Fxsr => (Leaf1Edx, 24),
+            Feature::Sse => (Leaf1Edx, 25),
+            Feature::Sse2 => (Leaf1Edx, 26),
+            Feature::FsGsBase => (Leaf7Ebx, 0),
+            Feature::Smep => (Leaf7Ebx, 7),
+            Feature::Invpcid => (Leaf7Ebx, 10),
+            Feature::Avx512F => (Leaf7Ebx, 16),
+            Feature::Smap => (Leaf7Ebx, 20),
+            Feature::Umip => (Leaf7Ecx, 2),
+            Feature::NoExecute => (ExtendedEdx, 20),
+            Feature::Page1GiB => (ExtendedEdx, 26),
+            Feature::Rdtscp => (ExtendedEdx, 27),
+            Feature::InvariantTsc => (PowerManagementEdx, 8),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheInfo {
+    pub level: u8,
+    pub kind: CacheKind,
+    /// The size of the cache in bytes.
+    pub size: u64,
+    pub line_size: u32,
+    pub ways: u32,
+}
+
+/// What the boot CPU reports through CPUID, read once at boot.
+pub struct CpuInfo {
+    vendor: Vendor,
+    vendor_string: [u8; 12],
+    family: u32,
+    model: u32,
+    stepping: u32,
+    leaf_1: CpuidResult,
+    leaf_7: CpuidResult,
+    extended_leaf_1: CpuidResult,
+    power_management: CpuidResult,
+    caches: [Option<CacheInfo>; MAX_CACHES],
+    physical_address_bits: u8,
+    virtual_address_bits: u8,
+    tsc_frequency: u64,
+}
+
+static CPU_INFO: Once<CpuInfo> = Once::new();
+
+/// Returns the CPU information, reading it first if needed.
+pub fn cpu_info() -> &'static CpuInfo {
+    CPU_INFO.call_once(CpuInfo::read)
+}
+
+/// Reads the CPU information, which calibrates the TSC if its frequency is not reported.
+pub fn init_cpu_info() {
+    cpu_info();
+}
+
+/// Shorthand for `cpu_info().has_feature(feature)`.
+pub fn has_feature(feature: Feature) -> bool {
+    cpu_info().has_feature(feature)
+}
+
+impl CpuInfo {
+    fn read() -> CpuInfo {
+        let empty = CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+
+        let leaf_0 = cpuid(0, 0);
+        let max_leaf = leaf_0.eax;
+        let max_extended_leaf = cpuid(0x8000_0000, 0).eax;
+        let leaf = |leaf: u32| {
+            let max = if leaf >= 0x8000_0000 {
+                max_extended_leaf
+            } else {
+                max_leaf
+            };
+            if leaf <= max {
+                cpuid(leaf, 0)
+            } else {
+                empty
+            }
+        };
+
+        let mut vendor_string = [0; 12];
+        vendor_string[0..4].copy_from_slice(&leaf_0.ebx.to_le_bytes());
+        vendor_string[4..8].copy_from_slice(&leaf_0.edx.to_le_bytes());
+        vendor_string[8..12].copy_from_slice(&leaf_0.ecx.to_le_bytes());
+        let vendor = match &vendor_string {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other,
+        };
+
+        let leaf_1 = leaf(1);
+        let stepping = leaf_1.eax & 0xf;
+        let base_model = (leaf_1.eax >> 4) & 0xf;
+        let base_family = (leaf_1.eax >> 8) & 0xf;
+        let extended_model = (leaf_1.eax >> 16) & 0xf;
+        let extended_family = (leaf_1.eax >> 20) & 0xff;
+        let family = if base_family == 0xf {
+            base_family + extended_family
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xf {
+            (extended_model << 4) | base_model
+        } else {
+            base_model
+        };
+
+        // CPUID.80000008H:EAX holds the physical address width in bits 0-7, and the linear one in
+        // bits 8-15.
+        let address_sizes = leaf(0x8000_0008);
+        let (physical_address_bits, virtual_address_bits) = if address_sizes.eax != 0 {
+            (address_sizes.eax as u8, (address_sizes.eax >> 8) as u8)
+        } else {
+            (36, 48)
+        };
+
+        let mut info = CpuInfo {
+            vendor,
+            vendor_string,
+            family,
+            model,
+            stepping,
+            leaf_1,
+            leaf_7: leaf(7),
+            extended_leaf_1: leaf(0x8000_0001),
+            power_management: leaf(0x8000_0007),
+            caches: [None; MAX_CACHES],
+            physical_address_bits,
+            virtual_address_bits,
+            tsc_frequency: 0,
+        };
+
+        info.caches = info.read_caches(max_leaf, max_extended_leaf);
+        info.tsc_frequency = info
+            .reported_tsc_frequency(max_leaf)
+            .unwrap_or_else(calibrate_tsc);
+        info
+    }
+
+    /// Reads the cache topology from the deterministic cache parameters leaf.
+    fn read_caches(
+        &self,
+        max_leaf: u32,
+        max_extended_leaf: u32,
+    ) -> [Option<CacheInfo>; MAX_CACHES] {
+        let mut caches = [None; MAX_CACHES];
+
+        // AMD reports the same layout in a leaf of its own, if topology extensions are supported.
+        let leaf = match self.vendor {
+            Vendor::Amd
+                if max_extended_leaf >= 0x8000_001d
+                    && self.extended_leaf_1.ecx & (1 << 22) != 0 =>
+            {
+                0x8000_001d
+            }
+            Vendor::Intel if max_leaf >= 4 => 4,
+            _ => return caches,
+        };
+
+        for (index, cache) in caches.iter_mut().enumerate() {
+            let result = cpuid(leaf, index as u32);
+            let kind = match result.eax & 0x1f {
+                1 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => break,
+            };
+
+            let line_size = (result.ebx & 0xfff) + 1;
+            let partitions = ((result.ebx >> 12) & 0x3ff) + 1;
+            let ways = ((result.ebx >> 22) & 0x3ff) + 1;
+            let sets = result.ecx + 1;
+
+            *cache = Some(CacheInfo {
+                level: ((result.eax >> 5) & 0x7) as u8,
+                kind,
+                size: ways as u64 * partitions as u64 * line_size as u64 * sets as u64,
+                line_size,
+                ways,
+            });
+        }
+
+        caches
+    }
+
+    /// Returns the TSC frequency in Hz if the CPU or hypervisor reports it.
+    fn reported_tsc_frequency(&self, max_leaf: u32) -> Option<u64> {
+        // CPUID.15H reports the TSC to crystal clock ratio in EBX/EAX and the crystal clock in ECX.
+        if max_leaf >= 0x15 {
+            let result = cpuid(0x15, 0);
+            if result.eax != 0 && result.ebx != 0 && result.ecx != 0 {
+                return Some(result.ecx as u64 * result.ebx as u64 / result.eax as u64);
+            }
+        }
+
+        // CPUID.16H:EAX reports the base frequency in MHz, which the TSC runs at on these CPUs.
+        if max_leaf >= 0x16 {
+            let base_frequency = cpuid(0x16, 0).eax & 0xffff;
+            if base_frequency != 0 {
+                return Some(base_frequency as u64 * 1_000_000);
+            }
+        }
+
+        // Hypervisors report the TSC frequency in kHz in their timing leaf.
+        if self.has_feature(Feature::Hypervisor) && cpuid(0x4000_0000, 0).eax >= 0x4000_0010 {
+            let frequency = cpuid(0x4000_0010, 0).eax;
+            if frequency != 0 {
+                return Some(frequency as u64 * 1000);
+            }
+        }
+
+        None
+    }
+
+    pub fn vendor(&self) -> Vendor {
+        self.vendor
+    }
+
+    /// Returns the vendor identification string, like `GenuineIntel`.
+    pub fn vendor_string(&self) -> &str {
+        str::from_utf8(&self.vendor_string).unwrap_or("Unknown")
+    }
+
+    /// Returns the family, model and stepping, with the extended fields folded in.
+    pub fn family_model_stepping(&self) -> (u32, u32, u32) {
+        (self.family, self.model, self.stepping)
+    }
+
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        let (register, bit) = feature.location();
+        let value = match register {
+            FeatureRegister::Leaf1Ecx => self.leaf_1.ecx,
+            FeatureRegister::Leaf1Edx => self.leaf_1.edx,
+            FeatureRegister::Leaf7Ebx => self.leaf_7.ebx,
+            FeatureRegister::Leaf7Ecx => self.leaf_7.ecx,
+            FeatureRegister::ExtendedEcx => self.extended_leaf_1.ecx,
+            FeatureRegister::ExtendedEdx => self.extended_leaf_1.edx,
+            FeatureRegister::PowerManagementEdx => self.power_management.edx,
+        };
+        value & (1 << bit) != 0
+    }
+
+    /// Returns the caches of the CPU, from the lowest level up.
+    pub fn caches(&self) -> impl Iterator<Item = &CacheInfo> {
+        self.caches.iter().flatten()
+    }
+
+    /// Returns the width of physical addresses in bits.
+    pub fn physical_address_bits(&self) -> u8 {
+        self.physical_address_bits
+    }
+
+    /// Returns the width of virtual addresses in bits.
+    pub fn virtual_address_bits(&self) -> u8 {
+        self.virtual_address_bits
+    }
+
+    /// Returns the frequency of the TSC in Hz.
+    ///
+    /// This is only stable across power states if [`Feature::InvariantTsc`] is supported.
+    pub fn tsc_frequency(&self) -> u64 {
+        self.tsc_frequency
+    }
+
+    /// Converts milliseconds into TSC ticks, saturating instead of overflowing.
+    pub fn ms_to_tsc_ticks(&self, ms: u64) -> u64 {
+        let ticks = ms as u128 * self.tsc_frequency as u128 / 1000;
+        ticks.min(u64::MAX as u128) as u64
+    }
+}
+
+/// Measures the TSC frequency against PIT channel 2.
+fn calibrate_tsc() -> u64 {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    unsafe {
+        // Enable the channel 2 gate and keep the speaker off.
+        let control = u8::read_port(PIT_CHANNEL_2_CONTROL);
+        u8::write_port(PIT_CHANNEL_2_CONTROL, (control & !0x2) | 0x1);
+
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary.
+        u8::write_port(PIT_COMMAND, 0b1011_0000);
+        u8::write_port(PIT_CHANNEL_2, count as u8);
+        u8::write_port(PIT_CHANNEL_2, (count >> 8) as u8);
+
+        // Restart the count by toggling the gate.
+        let control = u8::read_port(PIT_CHANNEL_2_CONTROL);
+        u8::write_port(PIT_CHANNEL_2_CONTROL, control & !0x1);
+        u8::write_port(PIT_CHANNEL_2_CONTROL, control | 0x1);
+
+        let start = _rdtsc();
+        // Bit 5 reflects the channel 2 output, which goes high once the count reaches zero.
+        while u8::read_port(PIT_CHANNEL_2_CONTROL) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = _rdtsc();
+
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
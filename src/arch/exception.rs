@@ -0,0 +1,388 @@
+use core::{arch::global_asm, fmt};
+
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        rflags::RFlags,
+    },
+    structures::idt::{PageFaultErrorCode, SelectorErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    io::serial::is_printing_here,
+    memory::vmm::{handle_page_fault, is_guard_page},
+    paging::mapper::is_mapped,
+    serial_print, serial_println,
+    task::scheduler::preempt,
+};
+
+use super::{
+    backtrace::print_backtrace_from,
+    interrupt::{dispatch_irq, FIRST_IRQ_VECTOR},
+    percpu::this_cpu,
+    symbols::Symbolized,
+};
+
+/// The names of the architectural exceptions, indexed by vector.
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING POINT",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING POINT",
+    "VIRTUALIZATION",
+    "CONTROL PROTECTION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION",
+    "VMM COMMUNICATION",
+    "SECURITY",
+    "RESERVED",
+];
+
+const DEBUG_VECTOR: u64 = 1;
+const NMI_VECTOR: u64 = 2;
+const BREAKPOINT_VECTOR: u64 = 3;
+const DOUBLE_FAULT_VECTOR: u64 = 8;
+const PAGE_FAULT_VECTOR: u64 = 14;
+const MACHINE_CHECK_VECTOR: u64 = 18;
+
+/// The amount of instruction bytes dumped at the faulting instruction pointer.
+const INSTRUCTION_DUMP_SIZE: u64 = 16;
+
+/// The `IA32_GS_BASE` MSR, read by the entry path of exceptions that can interrupt `swapgs`.
+const GS_BASE_MSR: u32 = 0xc000_0101;
+
+/// The state saved by the exception and IRQ stubs, laid out in the order it is pushed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions that do not push an error code, and for IRQs.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// Every vector gets a stub that pushes a dummy error code where the CPU does not push one, and
+// the vector number, so all of them share a single frame layout.
+global_asm!(
+    r#"
+.macro exception_stub vector, error_code
+exception_stub_\vector:
+.if \error_code == 0
+    push 0
+.endif
+    push \vector
+// Has to match `is_paranoid`.
+.if \vector == 1 || \vector == 2 || \vector == 8 || \vector == 18
+    jmp paranoid_interrupt_common
+.else
+    jmp interrupt_common
+.endif
+.endm
+
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 9, 15, 16, 18, 19, 20, 22, 23, 24, 25, 26, 27, 28, 31
+    exception_stub \vector, 0
+.endr
+.irp vector, 8, 10, 11, 12, 13, 14, 17, 21, 29, 30
+    exception_stub \vector, 1
+.endr
+
+.macro push_registers
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+.endm
+
+// IRQ stubs jump here as well. The CPL of the interrupted code is in the CS pushed by the CPU,
+// coming from user mode the kernel's GS base has to be swapped in. rbx remembers whether it has
+// to be swapped back, as it survives the call.
+.global interrupt_common
+interrupt_common:
+    push_registers
+    xor ebx, ebx
+    test qword ptr [rsp + 144], 3
+    jz .Linterrupt_dispatch
+    swapgs
+    mov ebx, 1
+    jmp .Linterrupt_dispatch
+
+// Debug exceptions, NMIs, double faults and machine checks can arrive right before the `swapgs`
+// on entry from user mode, or right after the one on the way back, where the CPL does not tell
+// which GS base is loaded. User mode always runs with a zero GS base, as it has no way to change
+// it, so the MSR is checked instead.
+paranoid_interrupt_common:
+    push_registers
+    xor ebx, ebx
+    mov ecx, {gs_base_msr}
+    rdmsr
+    or eax, edx
+    jnz .Linterrupt_dispatch
+    swapgs
+    mov ebx, 1
+
+.Linterrupt_dispatch:
+    cld
+    mov rdi, rsp
+    call {dispatch}
+    test ebx, ebx
+    jz 2f
+    swapgs
+2:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    // Drop the vector and error code.
+    add rsp, 16
+    iretq
+
+.pushsection .rodata
+.global exception_stubs
+.p2align 3
+exception_stubs:
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    .quad exception_stub_\vector
+.endr
+.popsection
+"#,
+    dispatch = sym interrupt_dispatch,
+    gs_base_msr = const GS_BASE_MSR,
+);
+
+extern "C" {
+    static exception_stubs: [u64; 32];
+}
+
+/// Returns the address of the entry stub for an exception vector.
+pub fn exception_stub(vector: u8) -> VirtAddr {
+    VirtAddr::new(unsafe { exception_stubs[vector as usize] })
+}
+
+/// Handles every exception and IRQ, only returns if execution can be resumed.
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    this_cpu().enter_interrupt();
+    handle_interrupt(frame);
+    this_cpu().leave_interrupt();
+
+    // Code that runs with interrupts disabled, like the scheduler itself, is never preempted.
+    // Neither are exceptions that take the paranoid path, the thread would be parked on their IST
+    // stack, which the next of them overwrites.
+    if RFlags::from_bits_truncate(frame.rflags).contains(RFlags::INTERRUPT_FLAG)
+        && !is_paranoid(frame.vector)
+    {
+        preempt();
+    }
+}
+
+/// Returns whether the exception enters through `paranoid_interrupt_common`, the stubs have to
+/// match.
+fn is_paranoid(vector: u64) -> bool {
+    matches!(
+        vector,
+        DEBUG_VECTOR | NMI_VECTOR | DOUBLE_FAULT_VECTOR | MACHINE_CHECK_VECTOR
+    )
+}
+
+fn handle_interrupt(frame: &mut InterruptFrame) {
+    if frame.vector >= FIRST_IRQ_VECTOR as u64 {
+        dispatch_irq(frame.vector as u8);
+        return;
+    }
+
+    match frame.vector {
+        DEBUG_VECTOR | NMI_VECTOR | BREAKPOINT_VECTOR => {
+            // These can interrupt a print, the report is dropped rather than deadlocking.
+            if is_printing_here() {
+                return;
+            }
+            serial_println!("{}", EXCEPTION_NAMES[frame.vector as usize]);
+            dump_frame(frame);
+            return;
+        }
+        PAGE_FAULT_VECTOR => {
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if let Ok(addr) = Cr2::read() {
+                if handle_page_fault(addr, error_code) {
+                    return;
+                }
+            }
+        }
+        DOUBLE_FAULT_VECTOR => {
+            // A page fault on a guard page can not be handled on the overflowed stack, which
+            // turns it into a double fault.
+            if let Ok(addr) = Cr2::read() {
+                if is_guard_page(addr) {
+                    fatal_exception(
+                        frame,
+                        format_args!("KERNEL STACK OVERFLOW\nAccessed Address: {:?}", addr),
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let name = EXCEPTION_NAMES[frame.vector as usize];
+    fatal_exception(frame, format_args!("Unhandled exception: {}", name));
+}
+
+/// Reports an exception that can not be recovered from and halts the CPU.
+///
+/// This does not panic, the panic handler would print a second backtrace from within the
+/// exception handler.
+fn fatal_exception(frame: &InterruptFrame, message: fmt::Arguments) -> ! {
+    serial_println!("{}", message);
+    dump_frame(frame);
+    print_backtrace_from(Some(frame.rip), frame.rbp);
+
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
+
+fn dump_frame(frame: &InterruptFrame) {
+    if has_error_code(frame.vector) {
+        serial_println!("Error Code: {:?}", ErrorCode(frame));
+    }
+
+    serial_println!("RIP: {}", Symbolized(frame.rip));
+    serial_println!("CS: {:#06x} RFLAGS: {:#018x}", frame.cs, frame.rflags);
+    serial_println!("RSP: {:#018x} SS: {:#06x}", frame.rsp, frame.ss);
+    serial_println!(
+        "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx
+    );
+    serial_println!(
+        "RDX: {:#018x} RSI: {:#018x} RDI: {:#018x}",
+        frame.rdx,
+        frame.rsi,
+        frame.rdi
+    );
+    serial_println!(
+        "RBP: {:#018x} R8:  {:#018x} R9:  {:#018x}",
+        frame.rbp,
+        frame.r8,
+        frame.r9
+    );
+    serial_println!(
+        "R10: {:#018x} R11: {:#018x} R12: {:#018x}",
+        frame.r10,
+        frame.r11,
+        frame.r12
+    );
+    serial_println!(
+        "R13: {:#018x} R14: {:#018x} R15: {:#018x}",
+        frame.r13,
+        frame.r14,
+        frame.r15
+    );
+    serial_println!(
+        "CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+
+    // Reading an unmapped instruction pointer would fault again.
+    let rip = VirtAddr::try_new(frame.rip).ok();
+    match rip.filter(|&rip| is_mapped(rip) && is_mapped(rip + (INSTRUCTION_DUMP_SIZE - 1))) {
+        Some(rip) => {
+            serial_print!("Instruction Bytes:");
+            for offset in 0..INSTRUCTION_DUMP_SIZE {
+                let byte = unsafe { (rip + offset).as_ptr::<u8>().read_volatile() };
+                serial_print!(" {:02x}", byte);
+            }
+            serial_println!();
+        }
+        None => serial_println!("Instruction Bytes: <unmapped>"),
+    }
+}
+
+/// Returns whether the CPU pushes an error code for the vector.
+fn has_error_code(vector: u64) -> bool {
+    matches!(vector, 8 | 10 | 11 | 12 | 13 | 14 | 17 | 21 | 29 | 30)
+}
+
+/// Decodes the error code of an exception frame.
+struct ErrorCode<'a>(&'a InterruptFrame);
+
+impl fmt::Debug for ErrorCode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.0.error_code;
+        match self.0.vector {
+            PAGE_FAULT_VECTOR => write!(f, "{:?}", PageFaultErrorCode::from_bits_truncate(code)),
+            // Invalid TSS, segment not present, stack segment and general protection faults
+            // report the selector that caused them.
+            10..=13 if code != 0 => write!(f, "{:?}", SelectorErrorCode::new_truncate(code)),
+            _ => write!(f, "{:#x}", code),
+        }
+    }
+}
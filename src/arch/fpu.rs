@@ -0,0 +1,206 @@
+use core::{alloc::Layout, arch::asm, ptr::NonNull};
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use spin::Once;
+use x86_64::{
+    instructions::interrupts,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        xcontrol::{XCr0, XCr0Flags},
+    },
+};
+
+use super::cpu::{cpuid, has_feature, Feature};
+
+/// The size of the legacy FXSAVE area.
+const FXSAVE_SIZE: usize = 512;
+
+/// XSAVE areas have to be 64 byte aligned, FXSAVE ones only need 16 bytes.
+const FPU_STATE_ALIGN: usize = 64;
+
+/// The default x87 control word, all exceptions masked.
+const DEFAULT_FCW: u16 = 0x037f;
+/// The offset of the x87 control word in the legacy area.
+const FCW_OFFSET: usize = 0;
+/// The default MXCSR, all exceptions masked.
+const DEFAULT_MXCSR: u32 = 0x1f80;
+/// The offset of MXCSR in the legacy area.
+const MXCSR_OFFSET: usize = 24;
+
+/// How extended state is saved, decided once at boot.
+#[derive(Debug, Clone, Copy)]
+enum SaveMethod {
+    Fxsave,
+    /// XSAVE with an area of the given size for every component enabled in XCR0.
+    Xsave(usize),
+}
+
+static SAVE_METHOD: Once<SaveMethod> = Once::new();
+
+fn save_method() -> SaveMethod {
+    *SAVE_METHOD.get().expect("The FPU is not initialized")
+}
+
+/// Enables the FPU, SSE and, when supported, XSAVE with AVX and AVX-512 state.
+///
+/// This has to run on every CPU.
+pub fn init_fpu() {
+    let has_xsave = has_feature(Feature::Xsave);
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if has_xsave {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+    }
+
+    let method = if has_xsave {
+        // CPUID.(EAX=0DH,ECX=0):EAX lists the state components the CPU supports.
+        let supported = XCr0Flags::from_bits_truncate(cpuid(0xd, 0).eax as u64);
+        let avx512 = XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+
+        let mut enabled = XCr0Flags::X87 | XCr0Flags::SSE;
+        if supported.contains(XCr0Flags::AVX) {
+            enabled |= XCr0Flags::AVX;
+            if supported.contains(avx512) {
+                enabled |= avx512;
+            }
+        }
+        unsafe { XCr0::write(enabled) };
+
+        // CPUID.(EAX=0DH,ECX=0):EBX is the area size for the components enabled in XCR0.
+        SaveMethod::Xsave(cpuid(0xd, 0).ebx as usize)
+    } else {
+        SaveMethod::Fxsave
+    };
+
+    SAVE_METHOD.call_once(|| method);
+}
+
+/// The FPU, SSE and AVX state of a thread.
+///
+/// Extended state is switched eagerly, the scheduler saves the state of the outgoing thread and
+/// restores the state of the incoming one.
+pub struct FpuState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+// Safe because the area is owned and only accessed through `&self` and `&mut self`.
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// Creates a state with all registers cleared and all exceptions masked.
+    pub fn new() -> FpuState {
+        let size = match save_method() {
+            SaveMethod::Fxsave => FXSAVE_SIZE,
+            SaveMethod::Xsave(size) => size,
+        };
+        let layout = Layout::from_size_align(size, FPU_STATE_ALIGN).unwrap();
+
+        let area = NonNull::new(unsafe { alloc_zeroed(layout) })
+            .unwrap_or_else(|| handle_alloc_error(layout));
+
+        // A zeroed XSAVE header restores every component to its initial state, but MXCSR and the
+        // legacy FXSAVE area are loaded as they are.
+        unsafe {
+            (area.as_ptr().add(FCW_OFFSET) as *mut u16).write(DEFAULT_FCW);
+            (area.as_ptr().add(MXCSR_OFFSET) as *mut u32).write(DEFAULT_MXCSR);
+        }
+
+        FpuState { area, layout }
+    }
+
+    /// Saves the extended state of the CPU into this state.
+    pub fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            match save_method() {
+                SaveMethod::Fxsave => asm!("fxsave64 [{}]", in(reg) area, options(nostack)),
+                // Save every component enabled in XCR0.
+                SaveMethod::Xsave(_) => asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack)
+                ),
+            }
+        }
+    }
+
+    /// Loads this state into the CPU.
+    pub fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            match save_method() {
+                SaveMethod::Fxsave => asm!("fxrstor64 [{}]", in(reg) area, options(nostack)),
+                SaveMethod::Xsave(_) => asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") u32::MAX,
+                    in("edx") u32::MAX,
+                    options(nostack)
+                ),
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
+
+/// A section in which the kernel may use SIMD instructions, see [`kernel_fpu_begin`].
+pub struct KernelFpuGuard {
+    /// The state of the interrupted thread.
+    saved: FpuState,
+    interrupts_enabled: bool,
+}
+
+/// Starts a section in which the kernel may use SIMD instructions, it ends when the guard is
+/// dropped.
+///
+/// The kernel is built without SSE, so the code using it has to enable it explicitly with
+/// `#[target_feature(enable = "...")]`. Interrupts are disabled for the duration of the section,
+/// as interrupt handlers and context switches do not preserve the kernel's extended state.
+pub fn kernel_fpu_begin() -> KernelFpuGuard {
+    let interrupts_enabled = interrupts::are_enabled();
+    interrupts::disable();
+
+    let mut saved = FpuState::new();
+    saved.save();
+
+    KernelFpuGuard {
+        saved,
+        interrupts_enabled,
+    }
+}
+
+/// Ends a SIMD section early, this is the same as dropping the guard.
+pub fn kernel_fpu_end(guard: KernelFpuGuard) {
+    drop(guard);
+}
+
+impl Drop for KernelFpuGuard {
+    fn drop(&mut self) {
+        self.saved.restore();
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -1,35 +1,141 @@
+use core::{
+    mem::ManuallyDrop,
+    ptr::{addr_of, addr_of_mut},
+};
+
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::{
-    registers::segmentation::{Segment, CS, DS},
-    structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+    instructions::tables::load_tss,
+    registers::segmentation::{Segment, CS, DS, ES, SS},
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+    VirtAddr,
 };
 
+use crate::memory::stack::KernelStack;
+
+/// The interrupt stack table index of the double fault stack.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The interrupt stack table index of the NMI stack.
+pub const NMI_IST_INDEX: u16 = 1;
+/// The interrupt stack table index of the machine check stack.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// The size of each interrupt stack table stack.
+const IST_STACK_SIZE: usize = 16 * 1024;
+
+/// A stack for the interrupt stack table of the bootstrap processor.
+///
+/// These are statics as its GDT is loaded before any memory can be allocated.
+#[repr(align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+static mut NMI_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+static mut MACHINE_CHECK_STACK: IstStack = IstStack([0; IST_STACK_SIZE]);
+
+/// Returns the address right after the stack, stacks grow downwards.
+fn ist_stack_top(stack: *const IstStack) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + IST_STACK_SIZE as u64
+}
+
 pub struct Segments {
-    kernel_code_selector: SegmentSelector,
-    kernel_data_selector: SegmentSelector,
+    pub kernel_code_selector: SegmentSelector,
+    pub kernel_data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
 }
 
+/// The task state segment of the bootstrap processor, it is mutable as the privilege stack
+/// changes with every thread, see [`PerCpu`](super::percpu::PerCpu).
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
-    pub static ref GDT: (GlobalDescriptorTable, Segments) = {
-        let mut gdt = GlobalDescriptorTable::new();
-
-        let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
-
-        (
-            gdt,
-            Segments {
-                kernel_code_selector,
-                kernel_data_selector,
-            },
-        )
-    };
+    /// The GDT of the bootstrap processor.
+    ///
+    /// Every CPU builds its GDT the same way, so these selectors are valid on all of them.
+    pub static ref GDT: (GlobalDescriptorTable, Segments) =
+        build_gdt(unsafe { &*addr_of!(TSS) });
+}
+
+/// The user segments follow the kernel segments in the order `sysret` expects them.
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Segments) {
+    let mut gdt = GlobalDescriptorTable::new();
+
+    let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.append(Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(Descriptor::user_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+
+    (
+        gdt,
+        Segments {
+            kernel_code_selector,
+            kernel_data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
+}
+
+/// Loads the GDT of the bootstrap processor, returning its TSS.
+pub fn init_gdt() -> *mut TaskStateSegment {
+    // Faults that may happen on a broken stack get stacks of their own, so they can still be
+    // reported.
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            ist_stack_top(addr_of!(DOUBLE_FAULT_STACK));
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack_top(addr_of!(NMI_STACK));
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] =
+            ist_stack_top(addr_of!(MACHINE_CHECK_STACK));
+    }
+
+    load_gdt(&GDT);
+    unsafe { addr_of_mut!(TSS) }
+}
+
+/// Gives an application processor a GDT and TSS of its own, which are never freed. Returns the
+/// TSS.
+///
+/// The interrupt stack table stacks are allocated, so this needs the VMM.
+pub fn init_ap_gdt() -> *mut TaskStateSegment {
+    let mut tss = Box::new(TaskStateSegment::new());
+    for index in [
+        DOUBLE_FAULT_IST_INDEX,
+        NMI_IST_INDEX,
+        MACHINE_CHECK_IST_INDEX,
+    ] {
+        let stack = ManuallyDrop::new(
+            KernelStack::new().expect("Could not allocate an interrupt stack table stack"),
+        );
+        tss.interrupt_stack_table[index as usize] = stack.top();
+    }
+
+    let tss = Box::into_raw(tss);
+    let gdt: &'static (GlobalDescriptorTable, Segments) =
+        Box::leak(Box::new(build_gdt(unsafe { &*tss })));
+    load_gdt(gdt);
+    tss
 }
 
-pub fn init_gdt() {
-    GDT.0.load();
+fn load_gdt(gdt: &'static (GlobalDescriptorTable, Segments)) {
+    let (gdt, segments) = gdt;
+
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.kernel_code_selector);
-        DS::set_reg(GDT.1.kernel_data_selector);
+        CS::set_reg(segments.kernel_code_selector);
+        DS::set_reg(segments.kernel_data_selector);
+        ES::set_reg(segments.kernel_data_selector);
+        // The bootloader's stack segment selector is not valid in this GDT, returning from an
+        // interrupt would fault on it.
+        SS::set_reg(segments.kernel_data_selector);
+        load_tss(segments.tss_selector);
     }
 }
@@ -1,21 +1,57 @@
 use lazy_static::lazy_static;
-use x86_64::{
-    instructions::hlt,
-    registers::control::Cr2,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
-};
+use x86_64::structures::idt::InterruptDescriptorTable;
 
-use crate::serial_println;
+use super::{
+    exception::exception_stub,
+    gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX},
+    interrupt::{irq_stub, FIRST_IRQ_VECTOR},
+};
 
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt[69].set_handler_fn(interrupt_handler);
+
+        // The reserved vectors are never raised, so they are left out.
+        unsafe {
+            idt.divide_error.set_handler_addr(exception_stub(0));
+            idt.debug.set_handler_addr(exception_stub(1));
+            idt.non_maskable_interrupt
+                .set_handler_addr(exception_stub(2))
+                .set_stack_index(NMI_IST_INDEX);
+            idt.breakpoint.set_handler_addr(exception_stub(3));
+            idt.overflow.set_handler_addr(exception_stub(4));
+            idt.bound_range_exceeded.set_handler_addr(exception_stub(5));
+            idt.invalid_opcode.set_handler_addr(exception_stub(6));
+            idt.device_not_available.set_handler_addr(exception_stub(7));
+            idt.double_fault
+                .set_handler_addr(exception_stub(8))
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+            idt.invalid_tss.set_handler_addr(exception_stub(10));
+            idt.segment_not_present.set_handler_addr(exception_stub(11));
+            idt.stack_segment_fault.set_handler_addr(exception_stub(12));
+            idt.general_protection_fault
+                .set_handler_addr(exception_stub(13));
+            idt.page_fault.set_handler_addr(exception_stub(14));
+            idt.x87_floating_point.set_handler_addr(exception_stub(16));
+            idt.alignment_check.set_handler_addr(exception_stub(17));
+            idt.machine_check
+                .set_handler_addr(exception_stub(18))
+                .set_stack_index(MACHINE_CHECK_IST_INDEX);
+            idt.simd_floating_point.set_handler_addr(exception_stub(19));
+            idt.virtualization.set_handler_addr(exception_stub(20));
+            idt.cp_protection_exception
+                .set_handler_addr(exception_stub(21));
+            idt.hv_injection_exception
+                .set_handler_addr(exception_stub(28));
+            idt.vmm_communication_exception
+                .set_handler_addr(exception_stub(29));
+            idt.security_exception.set_handler_addr(exception_stub(30));
+        }
+
+        for vector in FIRST_IRQ_VECTOR..=u8::MAX {
+            unsafe { idt[vector].set_handler_addr(irq_stub(vector)) };
+        }
+
         idt
     };
 }
@@ -23,38 +59,3 @@ lazy_static! {
 pub fn init_idt() {
     IDT.load();
 }
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _: u64) -> ! {
-    serial_println!("DOUBLE FAULT: \n{:#?}", stack_frame);
-    loop {
-        hlt();
-    }
-}
-
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _: u64,
-) {
-    serial_println!("GENERAL PROTECTION FAULT: \n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    serial_println!("PAGE FAULT: {:?}", stack_frame);
-    serial_println!("Accessed Address: {:?}", Cr2::read());
-    serial_println!("Error Code: {:?}", error_code);
-    serial_println!("{:#?}", stack_frame);
-    loop {
-        hlt();
-    }
-}
-
-extern "x86-interrupt" fn interrupt_handler(_stack_frame: InterruptStackFrame) {
-    serial_println!("got an interrupt yay")
-}
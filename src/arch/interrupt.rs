@@ -0,0 +1,112 @@
+use core::arch::global_asm;
+
+use alloc::{boxed::Box, vec::Vec};
+use x86_64::VirtAddr;
+
+use crate::{apic::end_of_interrupt, sync::spinlock::IrqSpinLock};
+
+/// The first vector that is not a CPU exception.
+pub const FIRST_IRQ_VECTOR: u8 = 32;
+/// The vector the local APIC raises spurious interrupts on.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IRQ_VECTOR_COUNT: usize = 256 - FIRST_IRQ_VECTOR as usize;
+
+/// The size of a single IRQ stub, they are padded so a stub can be found from its vector.
+const IRQ_STUB_SIZE: u64 = 16;
+
+type IrqHandler = Box<dyn Fn() + Send + Sync>;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: IrqSpinLock<Vec<IrqHandler>> = IrqSpinLock::new(Vec::new());
+
+/// The handlers registered for each IRQ vector, a vector can be shared between handlers.
+static IRQ_HANDLERS: [IrqSpinLock<Vec<IrqHandler>>; IRQ_VECTOR_COUNT] =
+    [NO_HANDLERS; IRQ_VECTOR_COUNT];
+
+/// A bitmap of the vectors handed out by [`allocate_vector`].
+static ALLOCATED_VECTORS: IrqSpinLock<[u64; 4]> = IrqSpinLock::new([0; 4]);
+
+// Every IRQ vector gets a stub that pushes a dummy error code and the vector number, and joins
+// the exception path, which calls `dispatch_irq`.
+global_asm!(
+    r#"
+.p2align 4
+.global irq_stubs
+irq_stubs:
+.set irq_vector, 32
+.rept 224
+.p2align 4
+    push 0
+    push irq_vector
+    jmp interrupt_common
+.set irq_vector, irq_vector + 1
+.endr
+"#
+);
+
+extern "C" {
+    static irq_stubs: [u8; 0];
+}
+
+/// Returns the address of the entry stub for an IRQ vector.
+pub fn irq_stub(vector: u8) -> VirtAddr {
+    assert!(
+        vector >= FIRST_IRQ_VECTOR,
+        "Vector {} is not an IRQ",
+        vector
+    );
+    let stubs = VirtAddr::from_ptr(unsafe { irq_stubs.as_ptr() });
+    stubs + (vector - FIRST_IRQ_VECTOR) as u64 * IRQ_STUB_SIZE
+}
+
+/// Allocates a free IRQ vector.
+pub fn allocate_vector() -> Option<u8> {
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    let vector = (FIRST_IRQ_VECTOR..SPURIOUS_VECTOR)
+        .find(|&vector| allocated[vector as usize / 64] & (1 << (vector % 64)) == 0)?;
+    allocated[vector as usize / 64] |= 1 << (vector % 64);
+    Some(vector)
+}
+
+/// Releases a vector returned by [`allocate_vector`], its handlers are removed.
+pub fn free_vector(vector: u8) {
+    IRQ_HANDLERS[(vector - FIRST_IRQ_VECTOR) as usize]
+        .lock()
+        .clear();
+    ALLOCATED_VECTORS.lock()[vector as usize / 64] &= !(1 << (vector % 64));
+}
+
+/// Registers a handler for an IRQ vector.
+///
+/// Every handler registered on a vector runs when it is raised, so level triggered lines can be
+/// shared between devices. Handlers run with interrupts disabled and must not register handlers
+/// themselves, the local APIC is acknowledged once all of them ran.
+pub fn register_irq_handler(vector: u8, handler: impl Fn() + Send + Sync + 'static) {
+    assert!(
+        (FIRST_IRQ_VECTOR..SPURIOUS_VECTOR).contains(&vector),
+        "Vector {} can not have handlers",
+        vector
+    );
+
+    IRQ_HANDLERS[(vector - FIRST_IRQ_VECTOR) as usize]
+        .lock()
+        .push(Box::new(handler));
+}
+
+/// Runs the handlers registered for the vector.
+pub(super) fn dispatch_irq(vector: u8) {
+    // Spurious interrupts must not be acknowledged.
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+
+    for handler in IRQ_HANDLERS[(vector - FIRST_IRQ_VECTOR) as usize]
+        .lock()
+        .iter()
+    {
+        handler();
+    }
+
+    end_of_interrupt();
+}
@@ -1,67 +1,138 @@
-use core::ops::DerefMut;
+use core::{arch::asm, mem::ManuallyDrop, ops::DerefMut};
 
 use limine::{
     framebuffer::Framebuffer,
     memory_map::Entry,
     request::{
-        BootloaderInfoRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, RsdpRequest,
-        StackSizeRequest,
+        BootloaderInfoRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, ModuleRequest,
+        RsdpRequest, SmpRequest, StackSizeRequest,
     },
+    response::SmpResponse,
 };
 
 use crate::{
     acpi::{get_acpi, init_acpi, lai::init_lai},
     apic::init_apic,
     display::init_display,
-    memory::heap::init_heap,
+    memory::{heap::init_heap, reclaim::init_reclaim, stack::KernelStack, vmm::init_vmm},
     paging::{
         frame::{get_frame_allocator, init_allocator},
-        mapper::{get_page_mapper, init_mapper},
+        mapper::{get_page_mapper, init_mapper, init_pat},
     },
     pci::init_pci,
+    serial_println,
 };
 
+pub mod backtrace;
+pub mod context;
+pub mod cpu;
+pub mod exception;
+pub mod fpu;
 pub mod gdt;
 pub mod idt;
+pub mod interrupt;
+pub mod percpu;
+pub mod smp;
+pub mod symbols;
+pub mod usermode;
 
 use crate::arch::gdt::init_gdt;
 
-use self::idt::init_idt;
+use self::{
+    cpu::{cpu_info, init_cpu_info},
+    fpu::init_fpu,
+    idt::init_idt,
+    percpu::{init_bsp_percpu, this_cpu},
+    smp::init_smp,
+    symbols::{init_symbols, SYMBOL_FILE_PATH},
+    usermode::init_syscalls,
+};
 
 #[no_mangle]
 pub extern "C" fn init_kernel() {
+    init_bsp_percpu();
     let limine_data = init_limine();
 
-    init_gdt();
+    init_cpu_info();
+    let (family, model, stepping) = cpu_info().family_model_stepping();
+    serial_println!(
+        "CPU: {} family {:#x} model {:#x} stepping {}, TSC at {} MHz",
+        cpu_info().vendor_string(),
+        family,
+        model,
+        stepping,
+        cpu_info().tsc_frequency() / 1_000_000
+    );
+
+    this_cpu().set_tss(init_gdt());
     init_idt();
-    init_allocator(limine_data.memory_map);
+    init_syscalls();
+    init_fpu();
     init_mapper(limine_data.physical_offset as u64);
+    init_pat();
+    init_allocator(limine_data.memory_map);
 
     init_heap(
         get_page_mapper().deref_mut(),
         get_frame_allocator().deref_mut(),
     );
+    init_reclaim(limine_data.memory_map);
+    init_vmm();
+
+    if let Some(symbol_file) = limine_data.symbol_file {
+        init_symbols(symbol_file);
+    }
 
     init_pci();
     unsafe { init_acpi(limine_data.rsdp_address) };
     unsafe { init_apic(get_acpi().deref_mut()) };
     init_lai();
 
+    if let Some(smp_response) = limine_data.smp_response {
+        init_smp(smp_response);
+    }
+
     init_display(limine_data.framebuffer);
 }
 
-static STACK_SIZE_REQUEST: StackSizeRequest = StackSizeRequest::new().with_size(4096);
+/// Leaves the stack provided by the bootloader and calls `entry` on a kernel owned stack.
+pub fn switch_to_kernel_stack(entry: extern "C" fn() -> !) -> ! {
+    // The stack is never freed, as `entry` never returns.
+    let stack = ManuallyDrop::new(KernelStack::new().expect("Could not allocate the kernel stack"));
+    let stack_top = stack.top();
+
+    unsafe {
+        asm!(
+            "mov rsp, {stack_top}",
+            "xor rbp, rbp",
+            "call {entry}",
+            stack_top = in(reg) stack_top.as_u64(),
+            entry = in(reg) entry,
+            options(noreturn)
+        )
+    }
+}
+
+/// The boot stack is only used until the kernel switches to its own stack, but initialization
+/// needs more than a page of it.
+static STACK_SIZE_REQUEST: StackSizeRequest = StackSizeRequest::new().with_size(64 * 1024);
 static BOOTLOADER_INFO: BootloaderInfoRequest = BootloaderInfoRequest::new();
 static FRAMEBUFFER_REQUEST: FramebufferRequest = FramebufferRequest::new();
 static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 pub static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+static MODULE_REQUEST: ModuleRequest = ModuleRequest::new();
+static SMP_REQUEST: SmpRequest = SmpRequest::new();
 
 struct LimineData<'a> {
     physical_offset: usize,
     framebuffer: Framebuffer<'a>,
     memory_map: &'a [&'a Entry],
     rsdp_address: *const u8,
+    /// The kernel symbol file module, if it was loaded.
+    symbol_file: Option<&'a [u8]>,
+    /// The application processors, which wait in bootloader memory until they are started.
+    smp_response: Option<&'a SmpResponse>,
 }
 
 fn init_limine() -> LimineData<'static> {
@@ -79,10 +150,23 @@ fn init_limine() -> LimineData<'static> {
 
     let rsdp_response = RSDP_REQUEST.get_response().unwrap();
 
+    // Modules live in memory that is never reclaimed, so the file can be referenced forever.
+    let symbol_file = MODULE_REQUEST.get_response().and_then(|response| {
+        response
+            .modules()
+            .iter()
+            .find(|module| module.path().ends_with(SYMBOL_FILE_PATH))
+            .map(|module| unsafe {
+                core::slice::from_raw_parts(module.addr(), module.size() as usize)
+            })
+    });
+
     LimineData {
         physical_offset,
         memory_map,
         framebuffer,
         rsdp_address: rsdp_response.address() as *const u8,
+        symbol_file,
+        smp_response: SMP_REQUEST.get_response(),
     }
 }
@@ -0,0 +1,240 @@
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::offset_of,
+    ops::Deref,
+    ptr::{self, addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+use spin::Once;
+use x86_64::{
+    registers::model_specific::{GsBase, KernelGsBase},
+    structures::tss::TaskStateSegment,
+    VirtAddr,
+};
+
+use crate::{apic::Apic, task::scheduler::CpuTasks};
+
+/// The data of a single CPU, found through the GS base while running in the kernel.
+///
+/// The entry stubs reach some of the fields directly, so the layout is fixed. User mode runs with
+/// its own GS base, the entry stubs `swapgs` when they come from user mode.
+#[repr(C)]
+pub struct PerCpu {
+    /// Points at the structure itself, as the GS base can not be read without an MSR access.
+    this: *const PerCpu,
+    /// The kernel stack `syscall` switches to, it belongs to the running thread.
+    syscall_kernel_stack: AtomicU64,
+    /// Scratch space for the user stack pointer while switching stacks on `syscall`.
+    syscall_user_stack: AtomicU64,
+    /// The amount of [`PerCpuGuard`]s, held spinlocks and other reasons the running thread may
+    /// not be switched out.
+    preempt_count: AtomicUsize,
+    /// How many interrupts and exceptions are being handled on top of each other.
+    interrupt_depth: AtomicUsize,
+    interrupt_count: AtomicU64,
+    syscall_count: AtomicU64,
+    id: usize,
+    /// The TSS of this CPU, the privilege stack in it changes with every thread.
+    tss: AtomicPtr<TaskStateSegment>,
+    apic: Once<Apic>,
+    /// Set by the timer when the running thread used up its time slice.
+    reschedule: AtomicBool,
+    tasks: UnsafeCell<CpuTasks>,
+}
+
+/// The offset of the `syscall` kernel stack, for the entry stubs.
+pub const SYSCALL_KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, syscall_kernel_stack);
+/// The offset of the `syscall` user stack scratch space, for the entry stubs.
+pub const SYSCALL_USER_STACK_OFFSET: usize = offset_of!(PerCpu, syscall_user_stack);
+
+/// The bootstrap processor's data is static, as spinlocks are taken before there is a heap.
+static mut BSP_CPU: PerCpu = PerCpu::new(0);
+
+impl PerCpu {
+    const fn new(id: usize) -> PerCpu {
+        PerCpu {
+            this: ptr::null(),
+            syscall_kernel_stack: AtomicU64::new(0),
+            syscall_user_stack: AtomicU64::new(0),
+            preempt_count: AtomicUsize::new(0),
+            interrupt_depth: AtomicUsize::new(0),
+            interrupt_count: AtomicU64::new(0),
+            syscall_count: AtomicU64::new(0),
+            id,
+            tss: AtomicPtr::new(ptr::null_mut()),
+            apic: Once::new(),
+            reschedule: AtomicBool::new(false),
+            tasks: UnsafeCell::new(CpuTasks::new()),
+        }
+    }
+
+    /// Returns the index of this CPU, the bootstrap processor is 0.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Returns the local APIC of this CPU.
+    pub fn apic(&self) -> &Apic {
+        self.apic.get().expect("The local APIC is not initialized")
+    }
+
+    /// Sets the local APIC of this CPU.
+    pub fn set_apic(&self, apic: Apic) {
+        self.apic.call_once(|| apic);
+    }
+
+    /// Returns whether the running thread may be switched out, not counting the guard this is
+    /// called through.
+    pub fn preemptible(&self) -> bool {
+        self.preempt_count.load(Ordering::Relaxed) <= 1
+    }
+
+    /// Returns how many interrupts and exceptions are being handled on top of each other.
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether this CPU is handling an interrupt or exception.
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth() > 0
+    }
+
+    /// Returns the amount of interrupts and exceptions handled by this CPU.
+    pub fn interrupt_count(&self) -> u64 {
+        self.interrupt_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the amount of system calls handled by this CPU.
+    pub fn syscall_count(&self) -> u64 {
+        self.syscall_count.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn enter_interrupt(&self) {
+        self.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        self.interrupt_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn leave_interrupt(&self) {
+        self.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Asks for the running thread to be switched out once the interrupt being handled returns.
+    pub fn request_reschedule(&self) {
+        self.reschedule.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether a reschedule was requested, and clears the request.
+    pub fn take_reschedule(&self) -> bool {
+        self.reschedule.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns the threads this CPU runs.
+    ///
+    /// Interrupts have to be disabled for as long as the reference is used, and no other
+    /// reference to them may exist.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn tasks(&self) -> &mut CpuTasks {
+        &mut *self.tasks.get()
+    }
+
+    pub(super) fn count_syscall(&self) {
+        self.syscall_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sets the TSS loaded on this CPU.
+    pub fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Relaxed);
+    }
+
+    /// Sets the stack this CPU switches to when entering the kernel from user mode.
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        self.syscall_kernel_stack
+            .store(stack_top.as_u64(), Ordering::Relaxed);
+        unsafe { (*self.tss.load(Ordering::Relaxed)).privilege_stack_table[0] = stack_top };
+    }
+}
+
+/// Access to the data of the CPU this runs on, the running thread is not switched out while it
+/// exists.
+pub struct PerCpuGuard {
+    cpu: &'static PerCpu,
+    /// The guard belongs to the CPU it was created on.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Deref for PerCpuGuard {
+    type Target = PerCpu;
+
+    fn deref(&self) -> &PerCpu {
+        self.cpu
+    }
+}
+
+impl Drop for PerCpuGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// Keeps the running thread on this CPU until [`preempt_enable`] is called.
+pub fn preempt_disable() {
+    // The count is raised through GS, so the thread can not move to another CPU in between
+    // finding the structure and raising it.
+    unsafe {
+        asm!(
+            "add qword ptr gs:[{preempt_count}], 1",
+            preempt_count = const offset_of!(PerCpu, preempt_count),
+            options(nostack)
+        )
+    };
+}
+
+/// Undoes a [`preempt_disable`], the thread is switched out on the next timer tick if nothing
+/// else keeps it on the CPU.
+pub fn preempt_enable() {
+    unsafe {
+        asm!(
+            "sub qword ptr gs:[{preempt_count}], 1",
+            preempt_count = const offset_of!(PerCpu, preempt_count),
+            options(nostack)
+        )
+    };
+}
+
+/// Returns the data of the CPU this runs on.
+pub fn this_cpu() -> PerCpuGuard {
+    preempt_disable();
+    let cpu = unsafe {
+        let cpu: *const PerCpu;
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &*cpu
+    };
+
+    PerCpuGuard {
+        cpu,
+        _not_send: PhantomData,
+    }
+}
+
+/// Points the GS base at the bootstrap processor's data, this has to run before anything takes a
+/// spinlock or an interrupt can arrive.
+pub fn init_bsp_percpu() {
+    unsafe { load_percpu(&mut *addr_of_mut!(BSP_CPU)) };
+}
+
+/// Allocates the data of an application processor, which is never freed.
+pub fn new_ap_percpu(id: usize) -> &'static mut PerCpu {
+    Box::leak(Box::new(PerCpu::new(id)))
+}
+
+/// Points the GS base of the CPU this runs on at its data.
+pub fn load_percpu(cpu: &'static mut PerCpu) {
+    cpu.this = addr_of!(*cpu);
+    GsBase::write(VirtAddr::from_ptr(cpu.this));
+    // User mode starts without a GS base.
+    KernelGsBase::write(VirtAddr::zero());
+}
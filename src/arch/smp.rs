@@ -0,0 +1,146 @@
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use limine::{response::SmpResponse, smp::Cpu};
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::PhysFrame,
+    PhysAddr,
+};
+
+use crate::{
+    apic::init_local_apic,
+    paging::mapper::init_pat,
+    serial_println,
+    task::{exit, scheduler::init_scheduler},
+};
+
+use super::{
+    fpu::init_fpu,
+    gdt::init_ap_gdt,
+    idt::init_idt,
+    percpu::{load_percpu, new_ap_percpu, this_cpu, PerCpu},
+    switch_to_kernel_stack,
+    usermode::init_syscalls,
+};
+
+/// The amount of CPUs running, including the bootstrap processor.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// The data of the application processor being started, it takes it over before anything else.
+static STARTING_CPU: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+
+/// The amount of application processors that finished their initialization.
+static APS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// The kernel's level 4 page table and CR3 flags, published once the page tables no longer live
+/// in bootloader memory.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// The amount of application processors that switched to the kernel's page tables.
+static APS_SWITCHED: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the amount of CPUs running.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+/// Starts every application processor reported by the bootloader and waits until they are
+/// initialized.
+///
+/// The application processors keep running on the bootloader's page tables until
+/// [`switch_ap_page_tables`] is called.
+pub fn init_smp(response: &SmpResponse) {
+    let bsp_lapic_id = response.bsp_lapic_id();
+
+    let mut started = 0;
+    for cpu in response.cpus() {
+        if cpu.lapic_id == bsp_lapic_id {
+            continue;
+        }
+
+        // The processors are started one at a time, so each picks up its own data.
+        STARTING_CPU.store(new_ap_percpu(started + 1), Ordering::Release);
+        cpu.goto_address.write(ap_entry);
+        started += 1;
+
+        while !STARTING_CPU.load(Ordering::Acquire).is_null() {
+            spin_loop();
+        }
+    }
+
+    while APS_ONLINE.load(Ordering::Acquire) < started {
+        spin_loop();
+    }
+    CPU_COUNT.store(started + 1, Ordering::Relaxed);
+
+    serial_println!("Started {} application processors", started);
+}
+
+/// Moves the application processors to the page tables the bootstrap processor is using, and
+/// waits until all of them did.
+///
+/// This has to run once the page tables were relocated out of bootloader memory, and before that
+/// memory is reclaimed.
+pub fn switch_ap_page_tables() {
+    let (frame, flags) = Cr3::read();
+    KERNEL_PAGE_TABLE.store(
+        frame.start_address().as_u64() | flags.bits(),
+        Ordering::Release,
+    );
+
+    let aps = cpu_count() - 1;
+    while APS_SWITCHED.load(Ordering::Acquire) < aps {
+        spin_loop();
+    }
+}
+
+/// Where the bootloader starts the application processors, on a stack in bootloader memory.
+unsafe extern "C" fn ap_entry(_cpu: &Cpu) -> ! {
+    // Spinlocks need the per-CPU data, so it is loaded before anything can take one.
+    let cpu = STARTING_CPU.swap(ptr::null_mut(), Ordering::AcqRel);
+    load_percpu(&mut *cpu);
+
+    switch_to_kernel_stack(ap_main)
+}
+
+extern "C" fn ap_main() -> ! {
+    this_cpu().set_tss(init_ap_gdt());
+    init_idt();
+    init_syscalls();
+    init_fpu();
+    init_pat();
+    unsafe { init_local_apic() };
+
+    APS_ONLINE.fetch_add(1, Ordering::Release);
+
+    let page_table = loop {
+        let page_table = KERNEL_PAGE_TABLE.load(Ordering::Acquire);
+        if page_table != 0 {
+            break page_table;
+        }
+        spin_loop();
+    };
+    unsafe {
+        Cr3::write(
+            PhysFrame::containing_address(PhysAddr::new(page_table & !0xfff)),
+            Cr3Flags::from_bits_truncate(page_table & 0xfff),
+        )
+    };
+
+    APS_SWITCHED.fetch_add(1, Ordering::Release);
+    let cpu = this_cpu();
+    serial_println!(
+        "CPU {} with local APIC ID {} is online",
+        cpu.id(),
+        cpu.apic().id()
+    );
+    drop(cpu);
+
+    // The CPU runs its idle thread until there is work to do.
+    init_scheduler();
+    exit()
+}
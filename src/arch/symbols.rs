@@ -0,0 +1,61 @@
+use core::{fmt, str};
+
+use alloc::vec::Vec;
+use spin::Once;
+
+/// The path of the symbol file module, generated from the kernel image by the runner.
+pub const SYMBOL_FILE_PATH: &[u8] = b"/kernel.sym";
+
+struct Symbol {
+    addr: u64,
+    name: &'static str,
+}
+
+/// The kernel function symbols, sorted by address.
+///
+/// This is never locked, so it can be used while panicking.
+static SYMBOLS: Once<Vec<Symbol>> = Once::new();
+
+/// Parses the symbol file, which lists one `address name` pair per line, sorted by address.
+///
+/// The heap has to be initialized first.
+pub fn init_symbols(file: &'static [u8]) {
+    SYMBOLS.call_once(|| {
+        let Ok(file) = str::from_utf8(file) else {
+            return Vec::new();
+        };
+
+        let mut symbols: Vec<Symbol> = file
+            .lines()
+            .filter_map(|line| {
+                let (addr, name) = line.split_once(' ')?;
+                let addr = u64::from_str_radix(addr, 16).ok()?;
+                Some(Symbol { addr, name })
+            })
+            .collect();
+        symbols.sort_unstable_by_key(|symbol| symbol.addr);
+        symbols
+    });
+}
+
+/// Returns the function containing the address and the offset into it.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let symbols = SYMBOLS.get()?;
+    let index = symbols
+        .partition_point(|symbol| symbol.addr <= addr)
+        .checked_sub(1)?;
+    let symbol = &symbols[index];
+    Some((symbol.name, addr - symbol.addr))
+}
+
+/// Formats an address as `function+offset` if it can be resolved.
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{:#018x} {}+{:#x}", self.0, name, offset),
+            None => write!(f, "{:#018x}", self.0),
+        }
+    }
+}
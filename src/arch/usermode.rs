@@ -0,0 +1,159 @@
+use core::arch::{asm, global_asm};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    VirtAddr,
+};
+
+use crate::syscall::dispatch;
+
+use super::{
+    gdt::GDT,
+    percpu::{this_cpu, SYSCALL_KERNEL_STACK_OFFSET, SYSCALL_USER_STACK_OFFSET},
+};
+
+/// The user state saved on the kernel stack by the `syscall` entry stub.
+///
+/// The syscall number is passed in `rax`, the arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and
+/// `r9`. The return value is placed in `rax`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// The user RFLAGS, saved by `syscall`.
+    pub r11: u64,
+    /// The user instruction pointer, saved by `syscall`.
+    pub rcx: u64,
+    pub rsp: u64,
+}
+
+// Interrupts are masked on entry, so nothing can run on the user stack or catch the kernel stack
+// half set up. `syscall` only comes from user mode, so GS always has to be swapped.
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_stack}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    push qword ptr gs:[{user_stack}]
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    cld
+    mov rdi, rsp
+    call {handler}
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    swapgs
+    sysretq
+"#,
+    handler = sym syscall_handler,
+    user_stack = const SYSCALL_USER_STACK_OFFSET,
+    kernel_stack = const SYSCALL_KERNEL_STACK_OFFSET,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables `syscall`/`sysret` and points them at the kernel entry stub.
+pub fn init_syscalls() {
+    let segments = &GDT.1;
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+        Star::write(
+            segments.user_code_selector,
+            segments.user_data_selector,
+            segments.kernel_code_selector,
+            segments.kernel_data_selector,
+        )
+        .expect("The GDT segments are not laid out for syscall");
+        LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    }
+    // Interrupts stay disabled until the entry stub switched to the kernel stack.
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+}
+
+/// Sets the kernel stack used when the running thread enters the kernel from user mode, through
+/// either `syscall` or an interrupt.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    this_cpu().set_kernel_stack(stack_top);
+}
+
+/// Drops into ring 3 at `entry` with the provided user stack.
+///
+/// The kernel stack for returning to the kernel has to be set with [`set_kernel_stack`] first,
+/// and both addresses must be mapped as user accessible.
+pub unsafe fn enter_user_mode(entry: VirtAddr, user_stack: VirtAddr) -> ! {
+    let segments = &GDT.1;
+    let rflags = RFlags::INTERRUPT_FLAG.bits() | 0x2;
+
+    asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        // Nothing of the kernel may leak into user mode.
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        // The kernel's GS base stays behind for the way back.
+        "swapgs",
+        "iretq",
+        data = in(reg) segments.user_data_selector.0 as u64,
+        code = in(reg) segments.user_code_selector.0 as u64,
+        stack = in(reg) user_stack.as_u64(),
+        rflags = in(reg) rflags,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    )
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    this_cpu().count_syscall();
+    dispatch(frame);
+}
@@ -2,22 +2,26 @@ use core::slice::from_raw_parts_mut;
 
 use limine::framebuffer::Framebuffer;
 use spin::Once;
+use x86_64::VirtAddr;
 
-use crate::sync::spinlock::{SpinLock, SpinLockGuard};
+use crate::{
+    paging::mapper::{convert_to_physical, ioremap_cache, CacheType, IoMapping},
+    sync::spinlock::{IrqSpinLock, IrqSpinLockGuard},
+};
 
 use self::font::FONT;
 
 pub mod font;
 
-static DISPLAY: Once<SpinLock<Display<'static>>> = Once::new();
+static DISPLAY: Once<IrqSpinLock<Display<'static>>> = Once::new();
 
-pub fn get_display<'a>() -> SpinLockGuard<'a, Display<'static>> {
+pub fn get_display<'a>() -> IrqSpinLockGuard<'a, Display<'static>> {
     DISPLAY.get().unwrap().lock()
 }
 
 pub fn init_display(framebuffer: Framebuffer<'static>) {
     let display: Display = Display::new(framebuffer);
-    DISPLAY.call_once(|| SpinLock::new(display));
+    DISPLAY.call_once(|| IrqSpinLock::new(display));
 }
 
 pub struct Display<'a> {
@@ -25,6 +29,8 @@ pub struct Display<'a> {
     pub width: u64,
     data: &'a mut [u8],
     bytes_per_pixel: usize,
+    /// The write-combining mapping backing `data`.
+    _mapping: IoMapping,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -46,12 +52,21 @@ impl AsRef<[u8; 4]> for Color {
 impl<'a> Display<'a> {
     pub fn new(framebuffer: Framebuffer<'a>) -> Display<'a> {
         let total_space = (framebuffer.pitch() * framebuffer.height()) as usize;
-        let data = unsafe { from_raw_parts_mut(framebuffer.addr(), total_space) };
+
+        // Remap the framebuffer as write-combining, the mapping provided by the bootloader is
+        // too slow for pushing pixels.
+        let physical_addr = convert_to_physical(VirtAddr::from_ptr(framebuffer.addr()))
+            .expect("The framebuffer is not mapped");
+        let mapping = ioremap_cache(physical_addr, total_space as u64, CacheType::WriteCombining)
+            .expect("Could not map the framebuffer");
+
+        let data = unsafe { from_raw_parts_mut(mapping.addr().as_mut_ptr(), total_space) };
         Display {
             data,
             height: framebuffer.height(),
             width: framebuffer.width(),
             bytes_per_pixel: (framebuffer.bpp() / 8) as usize,
+            _mapping: mapping,
         }
     }
 
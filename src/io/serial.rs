@@ -1,18 +1,28 @@
 use core::fmt::Write;
 
+use crate::sync::spinlock::IrqSpinLock;
 use lazy_static::lazy_static;
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts;
-
-use crate::sync::spinlock::SpinLock;
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    interrupts::without_interrupts(|| {
-        COM1.lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    COM1.lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
+}
+
+/// Sends raw bytes to the host through the serial interface.
+pub fn write_bytes(bytes: &[u8]) {
+    let mut port = COM1.lock();
+    for &byte in bytes {
+        port.send(byte);
+    }
+}
+
+/// Returns whether the CPU this runs on is in the middle of printing, so printing again would
+/// deadlock. Handlers of exceptions that can interrupt any code have to check this.
+pub fn is_printing_here() -> bool {
+    COM1.is_held_here()
 }
 
 /// Prints to the host through the serial interface.
@@ -33,9 +43,9 @@ macro_rules! serial_println {
 }
 
 lazy_static! {
-    pub static ref COM1: SpinLock<SerialPort> = {
+    pub static ref COM1: IrqSpinLock<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        SpinLock::new(serial_port)
+        IrqSpinLock::new(serial_port)
     };
 }
@@ -1,7 +1,9 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(asm_const)]
 #![feature(exposed_provenance)]
+#![feature(offset_of)]
 #![feature(strict_provenance)]
 
 mod acpi;
@@ -15,15 +17,19 @@ mod paging;
 mod pci;
 mod pic;
 mod sync;
+mod syscall;
+mod task;
 mod utils;
 
 use core::panic::PanicInfo;
 
-use arch::init_kernel;
+use arch::{backtrace::print_backtrace, init_kernel, switch_to_kernel_stack};
 use display::get_display;
+use memory::reclaim::reclaim_boot_memory;
 use net::driver::e1000::E1000Driver;
 use pci::get_pci;
-use x86_64::instructions::hlt;
+use task::scheduler::init_scheduler;
+use x86_64::instructions::{hlt, interrupts};
 
 use crate::display::Color;
 
@@ -33,24 +39,44 @@ extern crate alloc;
 
 pub extern "C" fn _start() -> ! {
     init_kernel();
+    switch_to_kernel_stack(kernel_main)
+}
+
+extern "C" fn kernel_main() -> ! {
+    unsafe { reclaim_boot_memory() };
 
-    E1000Driver::init(&mut get_pci()).expect("Could not initialize e1000 driver");
+    init_scheduler();
+    interrupts::enable();
 
-    let mut display = get_display();
+    task::spawn(|| {
+        // The driver lives for as long as the kernel runs.
+        let e1000 = E1000Driver::init(&mut get_pci()).expect("Could not initialize e1000 driver");
+        core::mem::forget(e1000);
+    })
+    .expect("Could not start the driver thread");
 
-    for y in 0..display.height {
-        for x in 0..display.width {
-            display.draw_pixel(x, y, Color::new(20, 223, 229));
+    task::spawn(|| {
+        let (width, height) = {
+            let display = get_display();
+            (display.width, display.height)
+        };
+
+        // The display lock keeps interrupts disabled, so it is only held for a row at a time.
+        for y in 0..height {
+            let mut display = get_display();
+            for x in 0..width {
+                display.draw_pixel(x, y, Color::new(20, 223, 229));
+            }
         }
-    }
+    })
+    .expect("Could not start the display thread");
 
-    loop {
-        hlt();
-    }
+    task::exit()
 }
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
     serial_println!("{:?}", info);
+    print_backtrace();
     loop {
         hlt();
     }
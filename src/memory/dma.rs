@@ -0,0 +1,192 @@
+use core::{marker::PhantomData, mem};
+
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::paging::{
+    frame::{get_frame_allocator, FRAME_SIZE},
+    mapper::convert_to_virtual,
+};
+
+/// Physically contiguous memory for devices to access through DMA.
+///
+/// The frames stay pinned for the lifetime of the region and are returned to the frame allocator
+/// when it is dropped.
+///
+/// Regions are always accessed write-back through the physical memory mapping. DMA is cache
+/// coherent on x86, and mapping the frames with another cache type as well would alias them with
+/// conflicting attributes.
+pub struct DmaRegion {
+    frames: PhysFrameRange<Size4KiB>,
+    size: usize,
+    virt: VirtAddr,
+}
+
+impl DmaRegion {
+    /// Allocates a zeroed region.
+    pub fn new(size: usize, align: usize) -> Option<DmaRegion> {
+        let frame_count = (size.max(1) + FRAME_SIZE - 1) / FRAME_SIZE;
+        let frames =
+            get_frame_allocator().allocate_contiguous(frame_count, align.max(FRAME_SIZE))?;
+        let virt = convert_to_virtual(frames.start.start_address());
+
+        unsafe {
+            virt.as_mut_ptr::<u8>()
+                .write_bytes(0, frame_count * FRAME_SIZE)
+        };
+
+        Some(DmaRegion { frames, size, virt })
+    }
+
+    /// Returns the physical address to hand to the device.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// Returns the virtual address the CPU accesses the region through.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// Returns the size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads a value at the provided byte offset.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        self.check_access::<T>(offset, 1);
+        unsafe { (self.virt + offset as u64).as_ptr::<T>().read_volatile() }
+    }
+
+    /// Writes a value at the provided byte offset.
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        self.check_access::<T>(offset, 1);
+        unsafe {
+            (self.virt + offset as u64)
+                .as_mut_ptr::<T>()
+                .write_volatile(value)
+        }
+    }
+
+    /// Reads consecutive values starting at the provided byte offset into `values`.
+    pub fn read_slice<T: Copy>(&self, offset: usize, values: &mut [T]) {
+        self.check_access::<T>(offset, values.len());
+        let ptr = (self.virt + offset as u64).as_ptr::<T>();
+        for (index, value) in values.iter_mut().enumerate() {
+            *value = unsafe { ptr.add(index).read_volatile() };
+        }
+    }
+
+    /// Writes `values` to consecutive values starting at the provided byte offset.
+    pub fn write_slice<T: Copy>(&mut self, offset: usize, values: &[T]) {
+        self.check_access::<T>(offset, values.len());
+        let ptr = (self.virt + offset as u64).as_mut_ptr::<T>();
+        for (index, &value) in values.iter().enumerate() {
+            unsafe { ptr.add(index).write_volatile(value) };
+        }
+    }
+
+    /// Checks that `count` values of `T` at the provided byte offset lie within the region.
+    fn check_access<T>(&self, offset: usize, count: usize) {
+        let end = mem::size_of::<T>()
+            .checked_mul(count)
+            .and_then(|size| size.checked_add(offset));
+        assert!(
+            end.is_some_and(|end| end <= self.size),
+            "DMA access out of bounds"
+        );
+        assert!(
+            (self.virt.as_u64() as usize + offset) % mem::align_of::<T>() == 0,
+            "Unaligned DMA access"
+        );
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        unsafe { get_frame_allocator().free_contiguous(self.frames) };
+    }
+}
+
+// Safe because the region owns its frames and is only accessed through volatile reads and writes.
+unsafe impl Send for DmaRegion {}
+
+/// A DMA region holding an array of `T`, like a descriptor ring.
+pub struct DmaBuffer<T> {
+    region: DmaRegion,
+    len: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + Default> DmaBuffer<T> {
+    /// Allocates a buffer of `len` default initialized elements.
+    pub fn new(len: usize, align: usize) -> Option<DmaBuffer<T>> {
+        let align = align.max(mem::align_of::<T>());
+        let region = DmaRegion::new(len.checked_mul(mem::size_of::<T>())?, align)?;
+
+        let mut buffer = DmaBuffer {
+            region,
+            len,
+            _phantom: PhantomData,
+        };
+        for index in 0..len {
+            buffer.write(index, T::default());
+        }
+        Some(buffer)
+    }
+
+    /// Returns the amount of elements in the buffer.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the physical address of the buffer.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.region.phys_addr()
+    }
+
+    /// Returns the physical address of an element.
+    pub fn phys_addr_of(&self, index: usize) -> PhysAddr {
+        assert!(index < self.len, "DMA buffer index out of bounds");
+        self.phys_addr() + (index * mem::size_of::<T>()) as u64
+    }
+
+    /// Returns the virtual address of the buffer.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.region.virt_addr()
+    }
+
+    /// Reads an element.
+    pub fn read(&self, index: usize) -> T {
+        assert!(index < self.len, "DMA buffer index out of bounds");
+        self.region.read(index * mem::size_of::<T>())
+    }
+
+    /// Writes an element.
+    pub fn write(&mut self, index: usize, value: T) {
+        assert!(index < self.len, "DMA buffer index out of bounds");
+        self.region.write(index * mem::size_of::<T>(), value)
+    }
+
+    /// Reads consecutive elements starting at `start` into `values`.
+    pub fn read_slice(&self, start: usize, values: &mut [T]) {
+        self.check_range(start, values.len());
+        self.region.read_slice(start * mem::size_of::<T>(), values)
+    }
+
+    /// Writes `values` to consecutive elements starting at `start`.
+    pub fn write_slice(&mut self, start: usize, values: &[T]) {
+        self.check_range(start, values.len());
+        self.region.write_slice(start * mem::size_of::<T>(), values)
+    }
+
+    fn check_range(&self, start: usize, count: usize) {
+        assert!(
+            start.checked_add(count).is_some_and(|end| end <= self.len),
+            "DMA buffer range out of bounds"
+        );
+    }
+}
@@ -1,102 +1,293 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
-    ptr,
+    mem, ptr,
 };
 
 use x86_64::{
-    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+    structures::paging::{OffsetPageTable, PageTableFlags},
     VirtAddr,
 };
 
-use crate::sync::spinlock::SpinLock;
+use crate::{
+    paging::{
+        frame::{get_frame_allocator, BuddyFrameAllocator},
+        mapper::{get_page_mapper, map_anonymous},
+    },
+    sync::spinlock::IrqSpinLock,
+};
 
 #[global_allocator]
-pub static GLOBAL_ALLOCATOR: SpinLock<BumpAllocator> = SpinLock::new(BumpAllocator::new_empty());
-
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1000 * 1024;
-
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    let heap_start = VirtAddr::new(HEAP_START as u64);
-    let heap_end = heap_start + HEAP_SIZE.try_into().unwrap() - 1u64;
-    let heap_start_page = Page::containing_address(heap_start);
-    let heap_end_page = Page::containing_address(heap_end);
-
-    let page_range = Page::range_inclusive(heap_start_page, heap_end_page);
-
-    for page in page_range {
-        let frame = frame_allocator.allocate_frame().unwrap();
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper
-                .map_to(page, frame, flags, frame_allocator)
-                .unwrap()
-                .flush()
-        };
-    }
+pub static GLOBAL_ALLOCATOR: IrqSpinLock<Heap> = IrqSpinLock::new(Heap::new_empty());
+
+/// The heap start is 2 MiB aligned so it can be backed by huge pages.
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+/// The amount of memory mapped for the heap at boot.
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024;
+/// The heap never grows past this size.
+pub const HEAP_MAX_SIZE: usize = 512 * 1024 * 1024;
+/// The minimum amount of memory mapped every time the heap grows.
+const HEAP_GROWTH: usize = 2 * 1024 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+pub fn init_heap(mapper: &mut OffsetPageTable<'static>, frame_allocator: &mut BuddyFrameAllocator) {
+    let mapped = map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator);
+    assert_eq!(mapped, HEAP_SIZE, "Could not map the initial heap");
 
-    GLOBAL_ALLOCATOR
-        .lock()
-        .init(HEAP_START as *mut _, HEAP_SIZE)
+    let mut heap = GLOBAL_ALLOCATOR.lock();
+    unsafe { heap.allocator.init(HEAP_START as *mut _, HEAP_SIZE) };
+    heap.size = HEAP_SIZE;
+}
+
+/// Maps fresh frames at the provided heap address, returns the amount of bytes that were mapped.
+///
+/// This stops early once the frame allocator runs out of frames.
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> usize {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        map_anonymous(
+            mapper,
+            frame_allocator,
+            VirtAddr::new(start as u64),
+            size as u64,
+            flags,
+        ) as usize
+    }
 }
 
-pub struct BumpAllocator {
-    start: *mut u8,
-    next: *mut u8,
+/// The kernel heap, it grows on demand until it reaches [`HEAP_MAX_SIZE`].
+pub struct Heap {
+    allocator: LinkedListAllocator,
+    /// The amount of bytes currently mapped at [`HEAP_START`].
     size: usize,
 }
 
-impl BumpAllocator {
+impl Heap {
     pub const fn new_empty() -> Self {
         Self {
-            start: ptr::null::<u8>() as *mut u8,
-            next: ptr::null::<u8>() as *mut u8,
+            allocator: LinkedListAllocator::new_empty(),
             size: 0,
         }
     }
 
-    pub fn init(&mut self, start: *mut u8, size: usize) {
-        self.start = start;
-        self.next = start;
-        self.size = size;
+    /// Maps at least `min_size` more bytes at the end of the heap.
+    ///
+    /// Returns false if the heap hit its ceiling or physical memory ran out.
+    fn grow(&mut self, min_size: usize) -> bool {
+        let size = align_up(min_size.max(HEAP_GROWTH), PAGE_SIZE).min(HEAP_MAX_SIZE - self.size);
+        if size < min_size {
+            return false;
+        }
+
+        // The page mapper and frame allocator must never allocate on the heap, as the heap lock
+        // is held while growing.
+        let start = HEAP_START + self.size;
+        let mapped = map_heap_pages(
+            start,
+            size,
+            &mut get_page_mapper(),
+            &mut get_frame_allocator(),
+        );
+        if mapped == 0 {
+            return false;
+        }
+
+        unsafe { self.allocator.add_free_region(start, mapped) };
+        self.size += mapped;
+
+        true
     }
 }
 
-unsafe impl GlobalAlloc for SpinLock<BumpAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut inner = self.lock();
-        let allocation_size = layout.size().max(layout.align());
-        let aligned_address = (inner.next as usize + allocation_size - 1) & !(allocation_size - 1);
+/// A free block of heap memory, stored inside the block itself.
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode {
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
 
-        if aligned_address > inner.start as usize + inner.size {
-            return ptr::null::<u8>() as *mut u8;
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// Heap allocator that keeps the free blocks in a linked list sorted by address.
+///
+/// Allocations are served first-fit, and freed blocks are merged with their neighbours so the
+/// heap does not fragment into pieces too small to be reused.
+pub struct LinkedListAllocator {
+    head: ListNode,
+}
+
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn new_empty() -> Self {
+        Self {
+            head: ListNode {
+                size: 0,
+                next: ptr::null_mut(),
+            },
         }
-        let next = aligned_address + allocation_size;
-        inner.next = next as *mut u8;
+    }
 
-        aligned_address as *mut u8
+    /// Hands the memory region to the allocator.
+    ///
+    /// The caller must guarantee that the region is mapped, unused and only handed out once.
+    pub unsafe fn init(&mut self, start: *mut u8, size: usize) {
+        self.add_free_region(start as usize, size);
     }
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {}
-
-    unsafe fn realloc(
-        &self,
-        ptr: *mut u8,
-        layout: core::alloc::Layout,
-        new_size: usize,
-    ) -> *mut u8 {
+
+    /// Inserts a free region into the list, merging it with adjacent free regions.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        debug_assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        debug_assert!(size >= mem::size_of::<ListNode>());
+
+        // Find the last node that starts before the new region.
+        let mut prev: *mut ListNode = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+
+        let node = addr as *mut ListNode;
+        node.write(ListNode {
+            size,
+            next: (*prev).next,
+        });
+
+        // Merge with the following region.
+        let next = (*node).next;
+        if !next.is_null() && (*node).end_addr() == next as usize {
+            (*node).size += (*next).size;
+            (*node).next = (*next).next;
+        }
+
+        // Merge with the preceding region, the list head itself is never merged.
+        if prev != &mut self.head as *mut ListNode && (*prev).end_addr() == addr {
+            (*prev).size += (*node).size;
+            (*prev).next = (*node).next;
+        } else {
+            (*prev).next = node;
+        }
+    }
+
+    /// Tries to carve an allocation out of the region, returning the allocation start address.
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Option<usize> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+
+        // The leftover in front of the allocation needs to be able to hold a list node.
+        let front = alloc_start - region.start_addr();
+        if front > 0 && front < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region.end_addr() {
+            return None;
+        }
+
+        // Same goes for the leftover behind it.
+        let back = region.end_addr() - alloc_end;
+        if back > 0 && back < mem::size_of::<ListNode>() {
+            return None;
+        }
+
+        Some(alloc_start)
+    }
+
+    /// Allocates a block of memory, returns a null pointer if no free block is big enough.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        let mut prev: *mut ListNode = &mut self.head;
+        while !(*prev).next.is_null() {
+            let region = (*prev).next;
+
+            if let Some(alloc_start) = Self::alloc_from_region(&*region, size, align) {
+                // Unlink the region and give back whatever is left on either side.
+                (*prev).next = (*region).next;
+
+                let region_start = (*region).start_addr();
+                let region_end = (*region).end_addr();
+                let alloc_end = alloc_start + size;
+
+                if alloc_start > region_start {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+                if region_end > alloc_end {
+                    self.add_free_region(alloc_end, region_end - alloc_end);
+                }
+
+                return alloc_start as *mut u8;
+            }
+
+            prev = region;
+        }
+
+        ptr::null_mut()
+    }
+
+    /// Frees a block of memory previously returned by [`LinkedListAllocator::allocate`].
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+
+    /// Adjusts the layout so every allocated block can later hold a list node.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(mem::align_of::<ListNode>());
+        let size = align_up(
+            layout.size().max(mem::size_of::<ListNode>()),
+            mem::align_of::<ListNode>(),
+        );
+        (size, align)
+    }
+}
+
+/// Aligns the address upwards, `align` has to be a power of two.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Interrupts are disabled while the heap is locked, so the scheduler and interrupt handlers can
+// allocate without waiting on a thread that was preempted while holding the lock.
+unsafe impl GlobalAlloc for IrqSpinLock<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.lock();
+        loop {
+            let ptr = heap.allocator.allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+
+            // Leave room for the worst case alignment padding.
+            let (size, align) = LinkedListAllocator::size_align(layout);
+            if !heap.grow(size + align + mem::size_of::<ListNode>()) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.lock().allocator.deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
 
         let new_ptr = self.alloc(new_layout);
 
-        if !new_ptr.is_null() && !ptr.is_null() {
-            core::ptr::copy_nonoverlapping(
-                ptr,
-                self.alloc(layout),
-                core::cmp::min(layout.size(), new_size),
-            );
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
             self.dealloc(ptr, layout);
         }
         new_ptr
@@ -1 +1,5 @@
+pub mod dma;
 pub mod heap;
+pub mod reclaim;
+pub mod stack;
+pub mod vmm;
@@ -0,0 +1,72 @@
+use alloc::vec::Vec;
+use limine::memory_map::Entry;
+use spin::Once;
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PhysFrame},
+    PhysAddr,
+};
+
+use crate::{
+    arch::smp::switch_ap_page_tables,
+    paging::{
+        frame::{get_frame_allocator, is_reclaimable, FRAME_SIZE},
+        mapper::relocate_page_tables,
+    },
+    serial_println,
+};
+
+/// The reclaimable regions of the memory map, recorded while the memory map is still around.
+static RECLAIMABLE_REGIONS: Once<Vec<PhysFrameRange>> = Once::new();
+
+/// Records the reclaimable regions of the memory map, the heap has to be initialized first.
+pub fn init_reclaim(memory_map: &[&Entry]) {
+    RECLAIMABLE_REGIONS.call_once(|| {
+        memory_map
+            .iter()
+            .filter(|e| is_reclaimable(e.entry_type))
+            .filter_map(|e| {
+                // ACPI reclaimable regions are not guaranteed to be page aligned, so only the
+                // frames that are fully covered get reclaimed.
+                let start = PhysAddr::new(e.base).align_up(FRAME_SIZE as u64);
+                let end = PhysAddr::new(e.base + e.length).align_down(FRAME_SIZE as u64);
+                if start >= end {
+                    return None;
+                }
+
+                Some(PhysFrame::range(
+                    PhysFrame::containing_address(start),
+                    PhysFrame::containing_address(end),
+                ))
+            })
+            .collect()
+    });
+}
+
+/// Hands the bootloader and ACPI reclaimable memory over to the frame allocator.
+///
+/// This has to run once the kernel no longer uses the Limine responses or the firmware ACPI
+/// tables, and never on the stack provided by the bootloader.
+pub unsafe fn reclaim_boot_memory() {
+    let regions = RECLAIMABLE_REGIONS.get().unwrap();
+    let is_reclaimable = |addr: PhysAddr| {
+        regions
+            .iter()
+            .any(|r| r.start.start_address() <= addr && addr < r.end.start_address())
+    };
+
+    // The bootloader built the page tables in its own memory.
+    relocate_page_tables(is_reclaimable);
+    // The application processors still walk the bootloader's copies.
+    switch_ap_page_tables();
+
+    let mut frame_allocator = get_frame_allocator();
+    let free_before = frame_allocator.free_frames();
+    for region in regions {
+        frame_allocator.add_free_range(*region);
+    }
+
+    serial_println!(
+        "Reclaimed {} KiB of boot memory",
+        (frame_allocator.free_frames() - free_before) * FRAME_SIZE as u64 / 1024
+    );
+}
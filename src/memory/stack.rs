@@ -0,0 +1,33 @@
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use super::vmm::{get_vmm, VirtRegion};
+
+/// The size of a kernel stack.
+pub const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+
+/// A kernel stack with an unmapped guard page on either side, freed when dropped.
+///
+/// Overflowing the stack faults on the guard page, which the double fault handler reports as a
+/// stack overflow.
+pub struct KernelStack {
+    region: VirtRegion,
+}
+
+impl KernelStack {
+    pub fn new() -> Option<KernelStack> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let region = get_vmm().allocate(KERNEL_STACK_SIZE, 1, flags)?;
+        Some(KernelStack { region })
+    }
+
+    /// Returns the address right after the stack, stacks grow downwards.
+    pub fn top(&self) -> VirtAddr {
+        self.region.end()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unsafe { get_vmm().free(self.region) };
+    }
+}
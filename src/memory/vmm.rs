@@ -0,0 +1,400 @@
+use core::ops::Bound::{Excluded, Unbounded};
+
+use alloc::collections::BTreeMap;
+use spin::Once;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+            Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    paging::{
+        frame::{get_frame_allocator, is_frame_allocator_held_here, FRAME_SIZE},
+        mapper::{
+            convert_to_virtual, get_page_mapper, is_page_mapper_held_here, map_anonymous,
+            map_range, unmap_range,
+        },
+    },
+    sync::spinlock::{IrqSpinLock, IrqSpinLockGuard},
+};
+
+/// The start of the kernel address space managed by the region allocator.
+pub const VMM_START: u64 = 0xffff_c000_0000_0000;
+/// The end of the kernel address space managed by the region allocator.
+pub const VMM_END: u64 = 0xffff_e000_0000_0000;
+
+/// The start of the address space handed out to user mode.
+///
+/// This stays clear of the lower half identity mapping set up by the bootloader, and the heap.
+pub const USER_START: u64 = 0x0000_1000_0000_0000;
+/// The end of the address space handed out to user mode.
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+const PAGE_SIZE: u64 = FRAME_SIZE as u64;
+const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+static VMM: Once<IrqSpinLock<Vmm>> = Once::new();
+static USER_VMM: Once<IrqSpinLock<Vmm>> = Once::new();
+
+/// Returns the kernel virtual address space manager.
+///
+/// Locks are taken in the order vmm, heap, page mapper, frame allocator: regions are allocated on
+/// the heap, which may need the page mapper to grow, and mapping them takes the page mapper and
+/// the frame allocator. The vmm must thus never be locked while holding any of the others.
+pub fn get_vmm<'a>() -> IrqSpinLockGuard<'a, Vmm> {
+    VMM.get().unwrap().lock()
+}
+
+/// Returns the manager of the user half of the address space.
+pub fn get_user_vmm<'a>() -> IrqSpinLockGuard<'a, Vmm> {
+    USER_VMM.get().unwrap().lock()
+}
+
+/// Initializes the virtual address space managers, the heap has to be initialized first.
+pub fn init_vmm() {
+    VMM.call_once(|| IrqSpinLock::new(Vmm::new(VMM_START, VMM_END)));
+    USER_VMM.call_once(|| IrqSpinLock::new(Vmm::new(USER_START, USER_END)));
+}
+
+/// Returns the manager of the part of the address space the address belongs to.
+fn vmm_for(addr: VirtAddr) -> Option<&'static IrqSpinLock<Vmm>> {
+    if (USER_START..USER_END).contains(&addr.as_u64()) {
+        USER_VMM.get()
+    } else {
+        VMM.get()
+    }
+}
+
+/// A range of kernel virtual memory handed out by the [`Vmm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRegion {
+    /// The first usable address of the region.
+    pub start: VirtAddr,
+    /// The usable size of the region in bytes, guard pages excluded.
+    pub size: u64,
+}
+
+impl VirtRegion {
+    /// Returns the address right after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns whether the address lies within the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// What backs a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Only the address range is reserved, pages are backed by fresh frames on first access.
+    Reserved,
+    /// Backed by frames owned by the region, they are freed together with it.
+    Anonymous,
+    /// Maps physical memory that is not owned by the region, like device memory.
+    Physical(PhysAddr),
+}
+
+/// Bookkeeping for a region, keyed by its start address.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionInfo {
+    /// The usable size of the region in bytes.
+    pub size: u64,
+    /// The size of the unmapped guard area on either side of the region in bytes.
+    pub guard_size: u64,
+    /// The flags the region is mapped with.
+    pub flags: PageTableFlags,
+    pub kind: RegionKind,
+}
+
+/// Allocates ranges of a part of the address space and maps them.
+pub struct Vmm {
+    regions: BTreeMap<u64, RegionInfo>,
+    /// The first address managed.
+    start: u64,
+    /// The address right after the last address managed.
+    end: u64,
+}
+
+impl Vmm {
+    fn new(start: u64, end: u64) -> Vmm {
+        Vmm {
+            regions: BTreeMap::new(),
+            start,
+            end,
+        }
+    }
+
+    /// Reserves a region without mapping anything, its pages are backed lazily once accessed.
+    ///
+    /// `size` is rounded up to whole pages, `guard_pages` unmapped pages are kept free on either
+    /// side of the region, so running off its end faults instead of hitting another region.
+    pub fn reserve(
+        &mut self,
+        size: u64,
+        align: u64,
+        guard_pages: u64,
+        flags: PageTableFlags,
+    ) -> Option<VirtRegion> {
+        self.insert(size, align, guard_pages, flags, RegionKind::Reserved)
+    }
+
+    /// Allocates a region backed by fresh frames.
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        guard_pages: u64,
+        flags: PageTableFlags,
+    ) -> Option<VirtRegion> {
+        // Large regions are aligned so they can be backed by huge pages.
+        let align = if size >= HUGE_PAGE_SIZE {
+            HUGE_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        let region = self.insert(size, align, guard_pages, flags, RegionKind::Anonymous)?;
+
+        let mapped = unsafe {
+            map_anonymous(
+                &mut get_page_mapper(),
+                &mut get_frame_allocator(),
+                region.start,
+                region.size,
+                flags,
+            )
+        };
+        if mapped < region.size {
+            // Out of physical memory, give back whatever was mapped.
+            unsafe { self.free(region) };
+            return None;
+        }
+
+        Some(region)
+    }
+
+    /// Maps `size` bytes of physical memory at `phys` into a new region.
+    ///
+    /// The physical address does not have to be page aligned, the returned region starts at the
+    /// virtual address matching `phys`.
+    pub fn map_physical(
+        &mut self,
+        phys: PhysAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Option<VirtRegion> {
+        let phys_start = phys.align_down(PAGE_SIZE);
+        let offset = phys - phys_start;
+        let size = align_up(size.checked_add(offset)?, PAGE_SIZE)?;
+
+        // Match the alignment of the physical range so huge pages can be used.
+        let align = if phys_start.is_aligned(HUGE_PAGE_SIZE) && size >= HUGE_PAGE_SIZE {
+            HUGE_PAGE_SIZE
+        } else {
+            PAGE_SIZE
+        };
+        let region = self.insert(size, align, 0, flags, RegionKind::Physical(phys_start))?;
+
+        let result = unsafe {
+            map_range(
+                &mut get_page_mapper(),
+                &mut *get_frame_allocator(),
+                region.start,
+                phys_start,
+                region.size,
+                flags,
+            )
+        };
+        if result.is_err() {
+            unsafe { self.free(region) };
+            return None;
+        }
+
+        Some(VirtRegion {
+            start: region.start + offset,
+            size: region.size - offset,
+        })
+    }
+
+    /// Unmaps and releases a region, frames of anonymous regions are freed as well.
+    ///
+    /// The caller must ensure the region is no longer in use.
+    pub unsafe fn free(&mut self, region: VirtRegion) {
+        let start = region.start.align_down(PAGE_SIZE);
+        let Some(info) = self.regions.remove(&start.as_u64()) else {
+            panic!("Freeing unknown region {:?}", region);
+        };
+
+        let mut mapper = get_page_mapper();
+        match info.kind {
+            RegionKind::Physical(_) => unmap_range(&mut mapper, start, info.size, None),
+            RegionKind::Reserved | RegionKind::Anonymous => unmap_range(
+                &mut mapper,
+                start,
+                info.size,
+                Some(&mut get_frame_allocator()),
+            ),
+        }
+    }
+
+    /// Returns the region containing the address, guard pages included.
+    pub fn find_region(&self, addr: VirtAddr) -> Option<(VirtRegion, RegionInfo)> {
+        let addr = addr.as_u64();
+
+        // The address is either past the start of the closest region below it, or in the front
+        // guard area of the closest region above it.
+        let below = self.regions.range(..=addr).next_back();
+        let above = self.regions.range((Excluded(addr), Unbounded)).next();
+
+        below
+            .into_iter()
+            .chain(above)
+            .find(|(&start, info)| {
+                start - info.guard_size <= addr && addr < start + info.size + info.guard_size
+            })
+            .map(|(&start, &info)| {
+                let region = VirtRegion {
+                    start: VirtAddr::new(start),
+                    size: info.size,
+                };
+                (region, info)
+            })
+    }
+
+    fn insert(
+        &mut self,
+        size: u64,
+        align: u64,
+        guard_pages: u64,
+        flags: PageTableFlags,
+        kind: RegionKind,
+    ) -> Option<VirtRegion> {
+        let size = align_up(size.max(1), PAGE_SIZE)?;
+        let guard_size = guard_pages.checked_mul(PAGE_SIZE)?;
+        let start = self.find_free(size, align.max(PAGE_SIZE), guard_size)?;
+
+        self.regions.insert(
+            start,
+            RegionInfo {
+                size,
+                guard_size,
+                flags,
+                kind,
+            },
+        );
+
+        Some(VirtRegion {
+            start: VirtAddr::new(start),
+            size,
+        })
+    }
+
+    /// Finds the first gap that fits the region and its guard pages.
+    fn find_free(&self, size: u64, align: u64, guard_size: u64) -> Option<u64> {
+        let fits = |candidate: u64, limit: u64| {
+            let start = align_up(candidate.checked_add(guard_size)?, align)?;
+            let end = start.checked_add(size)?.checked_add(guard_size)?;
+            (end <= limit).then_some(start)
+        };
+
+        let mut candidate = self.start;
+        for (&start, info) in self.regions.iter() {
+            if let Some(start) = fits(candidate, start - info.guard_size) {
+                return Some(start);
+            }
+            candidate = start + info.size + info.guard_size;
+        }
+        fits(candidate, self.end)
+    }
+}
+
+/// Returns whether the address lies in the guard area around a region.
+///
+/// This is meant for fault handlers, so it gives up if the fault happened while this CPU held
+/// the region allocator.
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let Some(vmm) = vmm_for(addr).filter(|vmm| !vmm.is_held_here()) else {
+        return false;
+    };
+    let vmm = vmm.lock();
+    matches!(vmm.find_region(addr), Some((region, _)) if !region.contains(addr))
+}
+
+/// Resolves a page fault in a reserved region by backing the faulting page with a zeroed frame.
+///
+/// Returns false if the fault can not be resolved, like faults outside of any region, in guard
+/// pages, or accesses the flags of the region do not allow.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let Some(vmm) = vmm_for(addr) else {
+        return false;
+    };
+
+    // Other CPUs may hold the locks needed to back the page, but a fault while this CPU holds one
+    // of them, like touching a lazily backed page while mapping memory, is a bug. Waiting for
+    // the lock would deadlock.
+    if vmm.is_held_here() || is_page_mapper_held_here() || is_frame_allocator_held_here() {
+        return false;
+    }
+
+    let vmm = vmm.lock();
+    let Some((region, info)) = vmm.find_region(addr) else {
+        return false;
+    };
+
+    if info.kind != RegionKind::Reserved || !region.contains(addr) {
+        return false;
+    }
+
+    // Lazily backed pages are never mapped with fewer permissions than the region has, so a
+    // protection violation is always a bad access.
+    let denied = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        || (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !info.flags.contains(PageTableFlags::WRITABLE))
+        || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && info.flags.contains(PageTableFlags::NO_EXECUTE))
+        || (error_code.contains(PageFaultErrorCode::USER_MODE)
+            && !info.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    if denied {
+        return false;
+    }
+
+    let mut mapper = get_page_mapper();
+    let mut frame_allocator = get_frame_allocator();
+    let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(&mut *frame_allocator) else {
+        return false;
+    };
+    unsafe {
+        convert_to_virtual(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, FRAME_SIZE)
+    };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, info.flags, &mut *frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        // Another CPU backed the page in the meantime.
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            true
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+/// Aligns the value upwards, `align` has to be a power of two. Returns `None` on overflow.
+fn align_up(value: u64, align: u64) -> Option<u64> {
+    Some(value.checked_add(align - 1)? & !(align - 1))
+}
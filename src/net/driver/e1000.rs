@@ -1,8 +1,14 @@
-use alloc::vec::Vec;
-use x86_64::{PhysAddr, VirtAddr};
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::PhysAddr;
 
 use crate::{
-    paging::mapper::convert_to_virtual,
+    acpi::lai::route_pci_pin,
+    apic::ioapic::{mask_gsi, route_gsi},
+    arch::{
+        interrupt::{allocate_vector, free_vector, register_irq_handler},
+        percpu::this_cpu,
+    },
+    paging::mapper::{ioremap, IoMapping},
     pci::{DeviceAddr, GeneralDevice, Pci, PciCapability, PciDevice},
     serial_println,
 };
@@ -15,9 +21,27 @@ pub const INTEL_VENDOR: u16 = 0x8086;
 /// Device ID for Intel 82577L (e1000e)
 pub const INTEL_82577L: u16 = 0x100e;
 
+/// The size of the register space behind BAR0.
+const E1000_REGISTERS_SIZE: u64 = 128 * 1024;
+
+/// Interrupt Cause Read Register, reading it acknowledges the pending causes.
+const E1000_ICR: usize = 0x00c0;
+
+/// Interrupt Mask Set Register
+const E1000_IMS: usize = 0x00d0;
+
+/// Interrupt Mask Clear Register
+const E1000_IMC: usize = 0x00d8;
+
+/// Link Status Change interrupt cause.
+const E1000_ICR_LSC: u32 = 1 << 2;
+
 #[allow(dead_code)]
 pub struct E1000Driver {
-    register_base_addr: VirtAddr,
+    registers: Arc<IoMapping>,
+    irq_vector: u8,
+    /// The global system interrupt the device raises, routed to `irq_vector`.
+    gsi: u32,
 }
 
 impl E1000Driver {
@@ -63,20 +87,49 @@ impl E1000Driver {
             // Enable bus mastering.
             pci.enable_bus_mastering(addr.bus, addr.slot, addr.function);
 
-            let result =
-                lai::pci_route_pin(0, addr.bus, addr.slot, addr.function, device.interrupt_pin)
-                    .unwrap();
+            let (gsi, trigger_mode, polarity) =
+                route_pci_pin(addr.bus, addr.slot, addr.function, device.interrupt_pin)?;
 
-            serial_println!("{:?}", result);
+            serial_println!("e1000 routed to GSI {}", gsi);
 
             // Enable memory mapped i/o
             pci.enable_mmio(addr.bus, addr.slot, addr.function);
 
-            // Get base addr.
-            let base_addr = PhysAddr::new((device.bar0 >> 4) as u64);
+            // Get base addr, the lower 4 bits of a memory BAR hold its flags.
+            let base_addr = PhysAddr::new((device.bar0 & !0xF) as u64);
+
+            let registers = ioremap(base_addr, E1000_REGISTERS_SIZE).ok_or(())?;
+
+            // Start from a clean slate, only link status changes are of interest for now.
+            unsafe {
+                registers.write(E1000_IMC, u32::MAX);
+                registers.read::<u32>(E1000_ICR);
+                registers.write(E1000_IMS, E1000_ICR_LSC);
+            }
+
+            let registers = Arc::new(registers);
+            let irq_vector = allocate_vector().ok_or(())?;
+
+            let handler_registers = registers.clone();
+            register_irq_handler(irq_vector, move || {
+                // The line may be shared, so there might be nothing pending.
+                let cause: u32 = unsafe { handler_registers.read(E1000_ICR) };
+                if cause & E1000_ICR_LSC != 0 {
+                    serial_println!("e1000 link status changed");
+                }
+            });
+
+            let apic_id = this_cpu().apic().id();
+            if route_gsi(gsi, irq_vector, apic_id, trigger_mode, polarity).is_err() {
+                unsafe { registers.write(E1000_IMC, u32::MAX) };
+                free_vector(irq_vector);
+                return Err(());
+            }
 
             let driver = E1000Driver {
-                register_base_addr: convert_to_virtual(base_addr),
+                registers,
+                irq_vector,
+                gsi,
             };
 
             return Ok(driver);
@@ -85,4 +138,12 @@ impl E1000Driver {
     }
 }
 
+impl Drop for E1000Driver {
+    fn drop(&mut self) {
+        unsafe { self.registers.write(E1000_IMC, u32::MAX) };
+        _ = mask_gsi(self.gsi);
+        free_vector(self.irq_vector);
+    }
+}
+
 impl NetworkDriver for E1000Driver {}
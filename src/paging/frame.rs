@@ -2,104 +2,353 @@ use limine::memory_map::EntryType;
 use spin::Once;
 use x86_64::{
     structures::paging::{
-        frame::PhysFrameRangeInclusive, FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB,
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
     },
     PhysAddr,
 };
 
-use crate::sync::spinlock::{SpinLock, SpinLockGuard};
+use crate::sync::spinlock::{IrqSpinLock, IrqSpinLockGuard};
+
+use super::mapper::convert_to_virtual;
 
 pub const FRAME_SIZE: usize = 4096;
-const MAX_BITMAP_ENTRIES: usize = 50000;
-const MAX_PHYSICAL_ADDRESS: usize = FRAME_SIZE * MAX_BITMAP_ENTRIES;
 
-static mut BITMAP: [u8; MAX_BITMAP_ENTRIES] = [0; MAX_BITMAP_ENTRIES];
+/// The largest block managed by the buddy allocator is 2^MAX_ORDER frames (1 GiB).
+pub const MAX_ORDER: usize = 18;
+
+/// Marks the end of a free list.
+const NO_BLOCK: u64 = u64::MAX;
 
-static FRAME_ALLOCATOR: Once<SpinLock<BitMapFrameAllocator<'static>>> = Once::new();
+static FRAME_ALLOCATOR: Once<IrqSpinLock<BuddyFrameAllocator>> = Once::new();
 
-pub fn get_frame_allocator<'a>() -> SpinLockGuard<'a, BitMapFrameAllocator<'static>> {
+pub fn get_frame_allocator<'a>() -> IrqSpinLockGuard<'a, BuddyFrameAllocator> {
     FRAME_ALLOCATOR.get().unwrap().lock()
 }
 
+/// Returns whether the CPU this runs on holds the frame allocator, for fault handlers.
+pub fn is_frame_allocator_held_here() -> bool {
+    FRAME_ALLOCATOR.get().is_some_and(IrqSpinLock::is_held_here)
+}
+
+/// Initializes the frame allocator, the page mapper has to be initialized first.
 pub fn init_allocator(memory_map: &'static [&'static limine::memory_map::Entry]) {
-    FRAME_ALLOCATOR.call_once(|| SpinLock::new(BitMapFrameAllocator::new(memory_map)));
+    FRAME_ALLOCATOR.call_once(|| IrqSpinLock::new(BuddyFrameAllocator::new(memory_map)));
+}
+
+/// Free list links, stored in the first frame of every free block.
+struct FreeBlock {
+    next: u64,
+    prev: u64,
 }
 
-/// Frame allocator based on: https://shell-storm.org/blog/Physical-page-frame-allocation-with-bitmap-algorithms/
-pub struct BitMapFrameAllocator<'a> {
-    memory_map: &'a [&'a limine::memory_map::Entry],
+/// Buddy frame allocator.
+///
+/// Memory is managed in naturally aligned blocks of 2^order frames. Every order has its own free
+/// list, so allocating and freeing only touches `MAX_ORDER` lists at most.
+pub struct BuddyFrameAllocator {
+    /// The first free block of every order, as a frame number.
+    free_lists: [u64; MAX_ORDER + 1],
+    /// For every order, one bit per block that is set while the block sits in its free list.
+    free_map: &'static mut [u8],
+    /// The bit offset of every order inside `free_map`.
+    free_map_offsets: [usize; MAX_ORDER + 1],
+    /// The amount of frames up to the highest usable physical address.
+    frame_count: u64,
+    /// The amount of frames that are currently free.
+    free_frames: u64,
 }
 
-impl BitMapFrameAllocator<'_> {
-    pub fn new<'a>(memory_map: &'a [&limine::memory_map::Entry]) -> BitMapFrameAllocator<'a> {
-        BitMapFrameAllocator { memory_map }
+impl BuddyFrameAllocator {
+    /// Creates a frame allocator covering all usable memory.
+    ///
+    /// The allocator metadata is placed in the first usable region that is big enough to hold it.
+    /// Reclaimable regions are covered too, but only handed out once they are freed.
+    pub fn new(memory_map: &[&limine::memory_map::Entry]) -> BuddyFrameAllocator {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|e| e.entry_type == EntryType::USABLE)
+        };
+
+        let highest_address = memory_map
+            .iter()
+            .filter(|e| e.entry_type == EntryType::USABLE || is_reclaimable(e.entry_type))
+            .map(|e| e.base + e.length)
+            .max()
+            .unwrap_or(0);
+        let frame_count = highest_address / FRAME_SIZE as u64;
+
+        let mut free_map_offsets = [0; MAX_ORDER + 1];
+        let mut free_map_bits = 0;
+        for (order, offset) in free_map_offsets.iter_mut().enumerate() {
+            *offset = free_map_bits;
+            free_map_bits += (frame_count >> order) as usize + 1;
+        }
+        let free_map_size = (free_map_bits + 7) / 8;
+
+        let free_map_entry = usable()
+            .find(|e| e.length as usize >= free_map_size)
+            .expect("No usable memory region can hold the frame allocator metadata");
+
+        let free_map = unsafe {
+            let ptr = convert_to_virtual(PhysAddr::new(free_map_entry.base)).as_mut_ptr::<u8>();
+            ptr.write_bytes(0, free_map_size);
+            core::slice::from_raw_parts_mut(ptr, free_map_size)
+        };
+
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [NO_BLOCK; MAX_ORDER + 1],
+            free_map,
+            free_map_offsets,
+            frame_count,
+            free_frames: 0,
+        };
+
+        let metadata_frames = ((free_map_size + FRAME_SIZE - 1) / FRAME_SIZE) as u64;
+        for entry in usable() {
+            let mut start = entry.base / FRAME_SIZE as u64;
+            let end = (entry.base + entry.length) / FRAME_SIZE as u64;
+
+            // Skip the frames holding the metadata.
+            if entry.base == free_map_entry.base {
+                start += metadata_frames;
+            }
+
+            if start < end {
+                allocator.free_range(start, end - start);
+            }
+        }
+
+        allocator
+    }
+
+    /// Returns the amount of free frames.
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
     }
 
-    fn frame_number(&self, frame: &PhysFrame) -> usize {
-        frame.start_address().as_u64() as usize / FRAME_SIZE
+    /// Allocates `count` physically contiguous frames, aligned to `align` bytes.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align: usize,
+    ) -> Option<PhysFrameRange<Size4KiB>> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        // Blocks are naturally aligned, so a big enough order takes care of the alignment.
+        let align_frames = (align / FRAME_SIZE).max(1);
+        let order = count.next_power_of_two().max(align_frames).trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let start = self.allocate_block(order)?;
+
+        // Give back the tail of the block that was not asked for.
+        let count = count as u64;
+        let excess = (1 << order) - count;
+        if excess > 0 {
+            self.free_range(start + count, excess);
+        }
+
+        let start_frame = Self::frame(start);
+        Some(PhysFrame::range(start_frame, start_frame + count))
     }
 
-    pub fn is_used(&self, frame: &PhysFrame) -> bool {
-        let num = self.frame_number(frame);
-        unsafe { (BITMAP[num / 8] >> (7 - num % 8) & 1) == 1 }
+    /// Frees frames previously returned by [`BuddyFrameAllocator::allocate_contiguous`].
+    ///
+    /// The caller must ensure that the frames are no longer in use.
+    pub unsafe fn free_contiguous(&mut self, range: PhysFrameRange<Size4KiB>) {
+        let start = Self::frame_number(range.start);
+        let end = Self::frame_number(range.end);
+        self.free_range(start, end - start);
     }
 
-    pub fn mark_used(&self, frame: &PhysFrame) {
-        assert!((frame.start_address().as_u64() as usize) < MAX_PHYSICAL_ADDRESS);
+    /// Hands a range of frames that was never allocated to the allocator, like reclaimed memory.
+    ///
+    /// Frames past the highest address covered by the allocator are ignored.
+    /// The caller must ensure that the frames are no longer in use.
+    pub unsafe fn add_free_range(&mut self, range: PhysFrameRange<Size4KiB>) {
+        let start = Self::frame_number(range.start);
+        let end = Self::frame_number(range.end).min(self.frame_count);
+        if start < end {
+            self.free_range(start, end - start);
+        }
+    }
 
-        let num = self.frame_number(frame);
+    /// Allocates a frame of a huge page size, which is a single naturally aligned block.
+    fn allocate_huge_frame<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let order = Self::order_of::<S>();
+        let block = self.allocate_block(order)?;
+        Some(PhysFrame::containing_address(
+            Self::frame(block).start_address(),
+        ))
+    }
 
-        unsafe { BITMAP[num / 8] |= 1 << (7 - num % 8) }
+    fn deallocate_huge_frame<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let block = frame.start_address().as_u64() / FRAME_SIZE as u64;
+        self.free_block(block, Self::order_of::<S>());
     }
 
-    pub fn mark_unused(&self, frame: &PhysFrame) {
-        assert!((frame.start_address().as_u64() as usize) < MAX_PHYSICAL_ADDRESS);
-        let num = self.frame_number(frame);
-        unsafe { BITMAP[num / 8] &= !(1 << (7 - num % 8)) }
+    /// Returns the order of the blocks backing a page of the provided size.
+    fn order_of<S: PageSize>() -> usize {
+        (S::SIZE / FRAME_SIZE as u64).trailing_zeros() as usize
     }
 
-    pub fn mark_range_used(&self, range: PhysFrameRangeInclusive<Size4KiB>) {
-        // TODO: This can be optimized.
-        range.into_iter().for_each(|f| {
-            self.mark_used(&f);
-        })
+    /// Frees an arbitrary range of frames by splitting it into the largest aligned blocks.
+    fn free_range(&mut self, mut start: u64, mut count: u64) {
+        while count > 0 {
+            let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+            while 1 << order > count {
+                order -= 1;
+            }
+
+            self.free_block(start, order);
+            start += 1 << order;
+            count -= 1 << order;
+        }
     }
 
-    pub fn mark_range_unused(&self, range: PhysFrameRangeInclusive<Size4KiB>) {
-        // TODO: This can be optimized.
-        range.into_iter().for_each(|f| {
-            self.mark_unused(&f);
-        });
+    /// Allocates a block of 2^order frames, returning the number of its first frame.
+    fn allocate_block(&mut self, order: usize) -> Option<u64> {
+        // Find the smallest free block that is big enough.
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NO_BLOCK)?;
+        let block = self.free_lists[current];
+        self.remove_free(block, current);
+
+        // Split it until it has the requested size, freeing the upper halves.
+        while current > order {
+            current -= 1;
+            self.push_free(block + (1 << current), current);
+        }
+
+        self.free_frames -= 1 << order;
+        Some(block)
     }
-}
 
-unsafe impl<'a> FrameAllocator<Size4KiB> for BitMapFrameAllocator<'a> {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frames = self
-            .memory_map
-            .iter()
-            .filter(|e| e.entry_type == EntryType::USABLE)
-            .map(|e| e.base..e.base + e.length)
-            .flat_map(|r| r.step_by(FRAME_SIZE))
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
-
-        for frame in frames {
-            if !self.is_used(&frame) {
-                self.mark_used(&frame);
-                return Some(frame);
+    /// Frees a block of 2^order frames, merging it with its buddy as long as the buddy is free.
+    fn free_block(&mut self, mut block: u64, mut order: usize) {
+        debug_assert!(!self.is_free(block, order), "double free of frame {block}");
+
+        self.free_frames += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if buddy + (1 << order) > self.frame_count || !self.is_free(buddy, order) {
+                break;
             }
+
+            self.remove_free(buddy, order);
+            block = block.min(buddy);
+            order += 1;
         }
 
-        None
+        self.push_free(block, order);
+    }
+
+    fn push_free(&mut self, block: u64, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            Self::node(block).write(FreeBlock {
+                next: head,
+                prev: NO_BLOCK,
+            });
+            if head != NO_BLOCK {
+                (*Self::node(head)).prev = block;
+            }
+        }
+        self.free_lists[order] = block;
+        self.set_free(block, order, true);
+    }
+
+    fn remove_free(&mut self, block: u64, order: usize) {
+        let FreeBlock { next, prev } = unsafe { Self::node(block).read() };
+        unsafe {
+            if next != NO_BLOCK {
+                (*Self::node(next)).prev = prev;
+            }
+            if prev != NO_BLOCK {
+                (*Self::node(prev)).next = next;
+            }
+        }
+        if self.free_lists[order] == block {
+            self.free_lists[order] = next;
+        }
+        self.set_free(block, order, false);
+    }
+
+    fn is_free(&self, block: u64, order: usize) -> bool {
+        let bit = self.free_map_offsets[order] + (block >> order) as usize;
+        (self.free_map[bit / 8] >> (bit % 8)) & 1 == 1
+    }
+
+    fn set_free(&mut self, block: u64, order: usize, free: bool) {
+        let bit = self.free_map_offsets[order] + (block >> order) as usize;
+        if free {
+            self.free_map[bit / 8] |= 1 << (bit % 8);
+        } else {
+            self.free_map[bit / 8] &= !(1 << (bit % 8));
+        }
+    }
+
+    /// Returns the free list links of a free block, accessed through the HHDM.
+    fn node(block: u64) -> *mut FreeBlock {
+        convert_to_virtual(Self::frame(block).start_address()).as_mut_ptr()
+    }
+
+    fn frame(number: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(number * FRAME_SIZE as u64))
+    }
+
+    fn frame_number(frame: PhysFrame) -> u64 {
+        frame.start_address().as_u64() / FRAME_SIZE as u64
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_block(0).map(Self::frame)
     }
 }
 
-impl FrameDeallocator<Size4KiB> for BitMapFrameAllocator<'_> {
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        self.mark_unused(&frame);
+        self.free_block(Self::frame_number(frame), 0);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_huge_frame()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_huge_frame(frame)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_huge_frame()
     }
 }
 
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate_huge_frame(frame)
+    }
+}
+
+/// Returns whether memory of this type can be reclaimed once the kernel is done booting.
+pub fn is_reclaimable(entry_type: EntryType) -> bool {
+    entry_type == EntryType::BOOTLOADER_RECLAIMABLE || entry_type == EntryType::ACPI_RECLAIMABLE
+}
+
 pub fn stringify_entry_type(entry_type: EntryType) -> &'static str {
     let type_id = unsafe { core::mem::transmute::<EntryType, u64>(entry_type) };
     match type_id {
@@ -1,24 +1,42 @@
+use core::{arch::asm, ops::DerefMut};
+
 use spin::Once;
 use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{OffsetPageTable, PageTable, Translate},
+    instructions::tlb,
+    registers::{control::Cr3, model_specific::Msr},
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
-use crate::sync::spinlock::{SpinLock, SpinLockGuard};
+use crate::{
+    arch::cpu::{has_feature, Feature},
+    memory::vmm::{get_vmm, VirtRegion},
+    sync::spinlock::{IrqSpinLock, IrqSpinLockGuard},
+};
+
+use super::frame::{get_frame_allocator, BuddyFrameAllocator};
 
-static PAGE_MAPPER: Once<SpinLock<OffsetPageTable>> = Once::new();
+static PAGE_MAPPER: Once<IrqSpinLock<OffsetPageTable>> = Once::new();
 
-pub fn get_page_mapper<'a>() -> SpinLockGuard<'a, OffsetPageTable<'static>> {
+pub fn get_page_mapper<'a>() -> IrqSpinLockGuard<'a, OffsetPageTable<'static>> {
     PAGE_MAPPER.get().unwrap().lock()
 }
 
+/// Returns whether the CPU this runs on holds the page mapper, for fault handlers.
+pub fn is_page_mapper_held_here() -> bool {
+    PAGE_MAPPER.get().is_some_and(IrqSpinLock::is_held_here)
+}
+
 pub static PHYSICAL_OFFSET: Once<u64> = Once::new();
 
 pub fn init_mapper(offset: u64) {
     PHYSICAL_OFFSET.call_once(|| offset);
     PAGE_MAPPER.call_once(|| unsafe {
-        SpinLock::new(OffsetPageTable::new(
+        IrqSpinLock::new(OffsetPageTable::new(
             active_page_table(),
             VirtAddr::new(offset),
         ))
@@ -43,3 +61,398 @@ pub unsafe fn active_page_table() -> &'static mut PageTable {
     let page_table_ptr: *mut PageTable = virt_addr.as_mut_ptr();
     &mut *page_table_ptr
 }
+
+/// Returns whether the address is mapped in the active page table.
+///
+/// This walks the page tables without taking the page mapper lock, so fault handlers can use it.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    effective_flags(addr).is_some()
+}
+
+/// Returns the permissions the active page table grants for the address, if it is mapped.
+///
+/// An access is only allowed if every level of the page table allows it, so the writable and user
+/// accessible flags are combined across all levels. Like [`is_mapped`] this takes no locks.
+pub fn effective_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let offset = *PHYSICAL_OFFSET.get()?;
+
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut table_addr = Cr3::read().0.start_address();
+    for (level, index) in indices.into_iter().enumerate() {
+        let table = unsafe { &*((table_addr.as_u64() + offset) as *const PageTable) };
+        let entry = &table[index];
+
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        flags &= entry.flags();
+
+        if level == indices.len() - 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Some(flags);
+        }
+        table_addr = entry.addr();
+    }
+    unreachable!()
+}
+
+/// Copies every page table that lives in memory matching `is_reclaimable` into freshly allocated
+/// frames and switches to the copies, so that memory can be freed afterwards.
+pub unsafe fn relocate_page_tables(is_reclaimable: impl Fn(PhysAddr) -> bool) {
+    let mut mapper = get_page_mapper();
+    let mut frame_allocator = get_frame_allocator();
+
+    let (level_4_frame, flags) = Cr3::read();
+    let new_level_4_frame = relocate_table(
+        level_4_frame,
+        4,
+        frame_allocator.deref_mut(),
+        &is_reclaimable,
+    );
+    Cr3::write(new_level_4_frame, flags);
+
+    *mapper = OffsetPageTable::new(active_page_table(), mapper.phys_offset());
+}
+
+/// Relocates a page table and all the tables below it, returning the frame of the table.
+unsafe fn relocate_table(
+    frame: PhysFrame,
+    level: u8,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    is_reclaimable: &impl Fn(PhysAddr) -> bool,
+) -> PhysFrame {
+    let frame = if is_reclaimable(frame.start_address()) {
+        let new_frame = frame_allocator
+            .allocate_frame()
+            .expect("Out of memory while relocating page tables");
+
+        core::ptr::copy_nonoverlapping(
+            convert_to_virtual(frame.start_address()).as_ptr::<PageTable>(),
+            convert_to_virtual(new_frame.start_address()).as_mut_ptr::<PageTable>(),
+            1,
+        );
+        new_frame
+    } else {
+        frame
+    };
+
+    // Level 1 entries and huge pages point to memory rather than to another table.
+    if level > 1 {
+        let table = &mut *convert_to_virtual(frame.start_address()).as_mut_ptr::<PageTable>();
+        for entry in table.iter_mut() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+
+            let child = PhysFrame::containing_address(entry.addr());
+            let new_child = relocate_table(child, level - 1, frame_allocator, is_reclaimable);
+            entry.set_addr(new_child.start_address(), flags);
+        }
+    }
+
+    frame
+}
+
+/// Returns whether the CPU supports 1 GiB pages.
+pub fn has_1gib_pages() -> bool {
+    has_feature(Feature::Page1GiB)
+}
+
+/// Returns the largest page size that can map `size` bytes at the provided addresses.
+fn largest_page_size(virt: VirtAddr, phys: PhysAddr, size: u64) -> u64 {
+    let fits = |page_size: u64| {
+        virt.is_aligned(page_size) && phys.is_aligned(page_size) && size >= page_size
+    };
+
+    if has_1gib_pages() && fits(Size1GiB::SIZE) {
+        Size1GiB::SIZE
+    } else if fits(Size2MiB::SIZE) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    }
+}
+
+/// Maps `size` bytes of physical memory at `phys` to `virt`, using 2 MiB and 1 GiB pages wherever
+/// the alignment of both addresses allows it.
+pub unsafe fn map_range(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut offset = 0;
+    while offset < size {
+        let (virt, phys) = (virt + offset, phys + offset);
+
+        offset += match largest_page_size(virt, phys, size - offset) {
+            Size1GiB::SIZE => map_page::<Size1GiB>(mapper, frame_allocator, virt, phys, flags)?,
+            Size2MiB::SIZE => map_page::<Size2MiB>(mapper, frame_allocator, virt, phys, flags)?,
+            _ => map_page::<Size4KiB>(mapper, frame_allocator, virt, phys, flags)?,
+        };
+    }
+    Ok(())
+}
+
+/// Maps `size` bytes of freshly allocated memory at `virt`, backed by huge frames where the
+/// alignment allows it. Returns the amount of bytes that were mapped.
+///
+/// This stops early once the frame allocator runs out of frames, for the memory itself or for
+/// the page tables mapping it.
+pub unsafe fn map_anonymous(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BuddyFrameAllocator,
+    virt: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> u64 {
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt + offset;
+        let remaining = size - offset;
+
+        // Fall back to smaller pages when no huge frame is available.
+        let can_map = |page_size: u64| virt.is_aligned(page_size) && remaining >= page_size;
+        let frame = if has_1gib_pages() && can_map(Size1GiB::SIZE) {
+            FrameAllocator::<Size1GiB>::allocate_frame(frame_allocator)
+                .map(|f| (f.start_address(), f.size()))
+        } else {
+            None
+        };
+        let frame = frame.or_else(|| {
+            can_map(Size2MiB::SIZE)
+                .then(|| FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator))
+                .flatten()
+                .map(|f| (f.start_address(), f.size()))
+        });
+        let frame = frame.or_else(|| {
+            FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+                .map(|f| (f.start_address(), f.size()))
+        });
+
+        let Some((phys, page_size)) = frame else {
+            break;
+        };
+
+        // Allocating a page table can fail as well, the frame is then given back.
+        if map_range(mapper, frame_allocator, virt, phys, page_size, flags).is_err() {
+            match page_size {
+                Size1GiB::SIZE => frame_allocator
+                    .deallocate_frame(PhysFrame::<Size1GiB>::containing_address(phys)),
+                Size2MiB::SIZE => frame_allocator
+                    .deallocate_frame(PhysFrame::<Size2MiB>::containing_address(phys)),
+                _ => frame_allocator
+                    .deallocate_frame(PhysFrame::<Size4KiB>::containing_address(phys)),
+            }
+            break;
+        }
+        offset += page_size;
+    }
+    offset
+}
+
+/// Maps a single page, returning its size.
+unsafe fn map_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    let frame = PhysFrame::<S>::containing_address(phys);
+
+    match mapper.map_to(page, frame, flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(S::SIZE)
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
+    }
+}
+
+/// Unmaps `size` bytes starting at `virt`, whatever page sizes they are mapped with.
+///
+/// The frames backing the range are handed back to `frame_allocator` if one is provided.
+pub unsafe fn unmap_range(
+    mapper: &mut OffsetPageTable<'static>,
+    virt: VirtAddr,
+    size: u64,
+    mut frame_allocator: Option<&mut BuddyFrameAllocator>,
+) {
+    let mut offset = 0;
+    while offset < size {
+        let addr = virt + offset;
+
+        offset += match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => match frame {
+                MappedFrame::Size4KiB(_) => {
+                    unmap_page::<Size4KiB>(mapper, addr, frame_allocator.as_deref_mut())
+                }
+                MappedFrame::Size2MiB(_) => {
+                    unmap_page::<Size2MiB>(mapper, addr, frame_allocator.as_deref_mut())
+                }
+                MappedFrame::Size1GiB(_) => {
+                    unmap_page::<Size1GiB>(mapper, addr, frame_allocator.as_deref_mut())
+                }
+            },
+            _ => Size4KiB::SIZE,
+        };
+    }
+}
+
+/// Unmaps a single page, returning its size.
+unsafe fn unmap_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    virt: VirtAddr,
+    frame_allocator: Option<&mut BuddyFrameAllocator>,
+) -> u64
+where
+    OffsetPageTable<'static>: Mapper<S>,
+    BuddyFrameAllocator: FrameDeallocator<S>,
+{
+    if let Ok((frame, flush)) = mapper.unmap(Page::<S>::containing_address(virt)) {
+        flush.flush();
+        if let Some(frame_allocator) = frame_allocator {
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+    S::SIZE
+}
+
+/// A mapping of device memory, unmapped again when dropped.
+pub struct IoMapping {
+    region: VirtRegion,
+}
+
+impl IoMapping {
+    /// Returns the virtual address the device memory is mapped at.
+    pub fn addr(&self) -> VirtAddr {
+        self.region.start
+    }
+
+    /// Returns the size of the mapping in bytes.
+    pub fn size(&self) -> u64 {
+        self.region.size
+    }
+
+    /// Reads a register at the provided byte offset.
+    pub unsafe fn read<T>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size() as usize);
+        (self.addr() + offset as u64).as_ptr::<T>().read_volatile()
+    }
+
+    /// Writes a register at the provided byte offset.
+    pub unsafe fn write<T>(&self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= self.size() as usize);
+        (self.addr() + offset as u64)
+            .as_mut_ptr::<T>()
+            .write_volatile(value)
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        unsafe { get_vmm().free(self.region) }
+    }
+}
+
+// Safe because the mapping is owned and only accessed through volatile reads and writes.
+unsafe impl Send for IoMapping {}
+unsafe impl Sync for IoMapping {}
+
+/// Maps `size` bytes of device memory at `phys` as uncacheable.
+///
+/// Drivers should use this instead of the HHDM, which does not necessarily cover device memory
+/// and maps it as cacheable.
+pub fn ioremap(phys: PhysAddr, size: u64) -> Option<IoMapping> {
+    ioremap_cache(phys, size, CacheType::Uncached)
+}
+
+/// Maps `size` bytes of device memory at `phys` with the provided cache type.
+pub fn ioremap_cache(phys: PhysAddr, size: u64, cache_type: CacheType) -> Option<IoMapping> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cache_type.flags();
+
+    let region = get_vmm().map_physical(phys, size, flags)?;
+    Some(IoMapping { region })
+}
+
+/// IA32_PAT MSR
+const IA32_PAT_MSR: u32 = 0x277;
+
+/// PAT memory type encodings.
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_UNCACHED_MINUS: u64 = 0x07;
+
+/// The memory type of a mapping, selected through the PAT.
+///
+/// Only the first four PAT entries are used, so the PAT bit never has to be set. Its position
+/// differs between 4 KiB and huge pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// Regular cacheable memory.
+    WriteBack,
+    /// Writes are buffered and combined into bursts, meant for framebuffers.
+    WriteCombining,
+    /// Uncacheable, but can be overridden by a write-combining MTRR.
+    UncachedMinus,
+    /// Strictly uncacheable, meant for device registers.
+    Uncached,
+}
+
+impl CacheType {
+    /// Returns the page table flags selecting this memory type.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheType::WriteBack => PageTableFlags::empty(),
+            CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheType::UncachedMinus => PageTableFlags::NO_CACHE,
+            CacheType::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Programs the PAT so every [`CacheType`] can be selected, this has to run on every CPU.
+///
+/// The first four entries match the power-on defaults, except for entry 1 which becomes
+/// write-combining instead of write-through. The last four are left as the bootloader set them,
+/// Limine maps the framebuffer through entry 5 and its mappings are still in use.
+pub fn init_pat() {
+    if !has_feature(Feature::Pat) {
+        return;
+    }
+
+    let mut msr = Msr::new(IA32_PAT_MSR);
+    let pat = (unsafe { msr.read() } & !0xffff_ffff)
+        | PAT_WRITE_BACK
+        | PAT_WRITE_COMBINING << 8
+        | PAT_UNCACHED_MINUS << 16
+        | PAT_UNCACHEABLE << 24;
+
+    unsafe {
+        // Flush the caches and the TLB, so no stale memory types are left around.
+        asm!("wbinvd", options(nostack, preserves_flags));
+        msr.write(pat);
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    tlb::flush_all();
+}
@@ -11,21 +11,21 @@ pub mod bar;
 
 use crate::{
     io::port::Port,
-    sync::spinlock::{SpinLock, SpinLockGuard},
+    sync::spinlock::{IrqSpinLock, IrqSpinLockGuard},
 };
 
 // Reference: https://wiki.osdev.org/PCI
 
-static PCI: Once<SpinLock<Pci>> = Once::new();
+static PCI: Once<IrqSpinLock<Pci>> = Once::new();
 
-pub fn get_pci<'a>() -> SpinLockGuard<'a, Pci> {
+pub fn get_pci<'a>() -> IrqSpinLockGuard<'a, Pci> {
     PCI.get().unwrap().lock()
 }
 
 /// Initializes the PCI instance. This can only be called once.
 pub fn init_pci() {
     let pci = Pci::new();
-    PCI.call_once(|| SpinLock::new(pci));
+    PCI.call_once(|| IrqSpinLock::new(pci));
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,69 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{
+    mutex::{Mutex, MutexGuard},
+    waitqueue::WaitQueue,
+};
+
+/// A condition variable, used together with a [`Mutex`] to wait for the data it protects to
+/// change.
+///
+/// Waiting threads may wake up without being notified, so they have to check their condition
+/// again.
+pub struct Condvar {
+    /// Raised by every notification, so a notification between unlocking the mutex and blocking
+    /// is not missed.
+    sequence: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            sequence: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex and blocks until the condition variable is notified, the mutex is locked
+    /// again before this returns.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex: &'a Mutex<T> = guard.mutex();
+        let sequence = self.sequence.load(Ordering::Acquire);
+        drop(guard);
+
+        self.waiters
+            .wait_until(|| self.sequence.load(Ordering::Acquire) != sequence);
+        mutex.lock()
+    }
+
+    /// Blocks until `condition` returns false for the data protected by the mutex.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes up one waiting thread.
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wakes up every waiting thread.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1 +1,6 @@
+pub mod condvar;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
 pub mod spinlock;
+pub mod waitqueue;
@@ -0,0 +1,91 @@
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::waitqueue::WaitQueue;
+
+/// Provides cross-thread access to `T`, threads waiting for it sleep instead of spinning.
+///
+/// This can not be locked from interrupt handlers, use a `SpinLock` there.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// The guard hands out `&T`, so it may only be shared if `T` is `Sync`.
+    _value: PhantomData<*mut T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<'a, T: Sync> Sync for MutexGuard<'a, T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks the mutex, blocking until it is available.
+    pub fn lock(&self) -> MutexGuard<T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+
+        self.waiters
+            .wait_until(|| !self.locked.swap(true, Ordering::Acquire));
+        MutexGuard {
+            mutex: self,
+            _value: PhantomData,
+        }
+    }
+
+    /// Tries to lock the mutex, returns `None` if it is already locked.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            return None;
+        }
+
+        Some(MutexGuard {
+            mutex: self,
+            _value: PhantomData,
+        })
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// Returns the mutex this guard locks.
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
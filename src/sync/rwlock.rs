@@ -0,0 +1,125 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::waitqueue::WaitQueue;
+
+/// Set in the state while a writer holds the lock, the other bits count the readers.
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+/// Provides cross-thread access to `T` for either many readers or a single writer, threads
+/// waiting for it sleep.
+///
+/// Readers are not held back by waiting writers, so a steady stream of readers can starve them.
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: Send> Send for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Locks for reading, blocking while a writer holds the lock.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        if let Some(guard) = self.try_read() {
+            return guard;
+        }
+
+        self.waiters.wait_until(|| self.try_read_state());
+        RwLockReadGuard { lock: self }
+    }
+
+    /// Locks for writing, blocking while anyone else holds the lock.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+
+        self.waiters.wait_until(|| self.try_write_state());
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Tries to lock for reading, returns `None` if a writer holds the lock.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        self.try_read_state()
+            .then_some(RwLockReadGuard { lock: self })
+    }
+
+    /// Tries to lock for writing, returns `None` if anyone else holds the lock.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.try_write_state()
+            .then_some(RwLockWriteGuard { lock: self })
+    }
+
+    fn try_read_state(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                (state & WRITER == 0).then_some(state + 1)
+            })
+            .is_ok()
+    }
+
+    fn try_write_state(&self) -> bool {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        // Only writers wait while there are readers.
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
@@ -0,0 +1,48 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::waitqueue::WaitQueue;
+
+/// A counting semaphore, threads waiting for a permit sleep.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with the provided amount of permits.
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a permit, blocking until one is available.
+    pub fn acquire(&self) {
+        if !self.try_acquire() {
+            self.waiters.wait_until(|| self.try_acquire());
+        }
+    }
+
+    /// Takes a permit, returns false if none is available.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Returns a permit, waking up a thread waiting for one.
+    ///
+    /// This can be called from interrupt handlers, for example to signal a completed transfer.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Returns the amount of permits available.
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
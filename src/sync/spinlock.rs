@@ -1,10 +1,21 @@
 use core::{
     cell::UnsafeCell,
+    mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
+use x86_64::instructions::interrupts;
+
+use crate::arch::percpu::{preempt_disable, preempt_enable, this_cpu};
+
+/// The owner of an [`IrqSpinLock`] no CPU holds.
+const NO_OWNER: usize = usize::MAX;
+
 /// Provides safe, cross-thread access to `T`
+///
+/// The holding thread is not switched out, so other CPUs never spin on a lock whose holder does
+/// not run.
 pub struct SpinLock<T> {
     lock: AtomicBool,
     value: UnsafeCell<T>,
@@ -29,6 +40,7 @@ impl<T> SpinLock<T> {
 
     /// Locks a spinlock.
     pub fn lock(&self) -> SpinLockGuard<T> {
+        preempt_disable();
         while self.lock.swap(true, Ordering::Acquire) {
             core::hint::spin_loop()
         }
@@ -38,6 +50,20 @@ impl<T> SpinLock<T> {
             value: self.value.get(),
         }
     }
+
+    /// Tries to lock a spinlock, returns `None` if it is already locked.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        preempt_disable();
+        if self.lock.swap(true, Ordering::Acquire) {
+            preempt_enable();
+            return None;
+        }
+
+        Some(SpinLockGuard {
+            lock: &self.lock,
+            value: self.value.get(),
+        })
+    }
 }
 
 impl<'a, T> Deref for SpinLockGuard<'a, T> {
@@ -57,5 +83,102 @@ impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
 impl<'a, T> Drop for SpinLockGuard<'a, T> {
     fn drop(&mut self) {
         self.lock.store(false, Ordering::Release);
+        preempt_enable();
+    }
+}
+
+/// A [`SpinLock`] that disables interrupts while it is held, so it can be shared with interrupt
+/// handlers.
+///
+/// Interrupts are restored to their previous state once the guard is dropped, guards of nested
+/// locks have to be dropped in reverse order.
+///
+/// The lock remembers the CPU holding it, so exception handlers can tell whether they interrupted
+/// its holder, which they would deadlock waiting for.
+pub struct IrqSpinLock<T> {
+    lock: SpinLock<T>,
+    /// The index of the CPU holding the lock, or [`NO_OWNER`].
+    owner: AtomicUsize,
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    owner: &'a AtomicUsize,
+    /// Whether interrupts were enabled before locking.
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinLock {
+            lock: SpinLock::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    /// Disables interrupts and locks the spinlock.
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let guard = self.lock.lock();
+        self.owner.store(this_cpu().id(), Ordering::Relaxed);
+
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            owner: &self.owner,
+            interrupts_enabled,
+        }
+    }
+
+    /// Returns whether the CPU this runs on holds the lock.
+    pub fn is_held_here(&self) -> bool {
+        // Only this CPU can set or clear its own index, so there is no race.
+        self.owner.load(Ordering::Relaxed) == this_cpu().id()
+    }
+
+    /// Tries to lock the spinlock, returns `None` if it is already locked.
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let interrupts_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let Some(guard) = self.lock.try_lock() else {
+            if interrupts_enabled {
+                interrupts::enable();
+            }
+            return None;
+        };
+        self.owner.store(this_cpu().id(), Ordering::Relaxed);
+
+        Some(IrqSpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            owner: &self.owner,
+            interrupts_enabled,
+        })
+    }
+}
+
+impl<'a, T> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // The lock has to be released before an interrupt can try to take it.
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_enabled {
+            interrupts::enable();
+        }
     }
 }
@@ -0,0 +1,62 @@
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::task::{self, Task};
+
+use super::spinlock::IrqSpinLock;
+
+/// A queue of threads blocked until some condition holds, interrupt handlers may wake them.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSpinLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns true.
+    ///
+    /// The condition is checked with the queue locked, so it can not miss a wakeup that follows
+    /// a change to it. Without a scheduler this polls the condition instead.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let mut waiters = self.waiters.lock();
+            if condition() {
+                return;
+            }
+
+            if let Some(current) = task::prepare_to_block() {
+                waiters.push_back(current);
+            }
+            drop(waiters);
+
+            task::block();
+        }
+    }
+
+    /// Wakes up the thread that waits the longest, returns false if no thread was waiting.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        // A thread woken up by something else is already running.
+        while let Some(task) = waiters.pop_front() {
+            if task::wake(&task) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wakes up every waiting thread, returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        waiters.drain(..).filter(task::wake).count()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,155 @@
+//! The system call interface for user mode.
+//!
+//! # ABI
+//!
+//! System calls are made with the `syscall` instruction. The system call number is passed in
+//! `rax` and up to six arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is
+//! returned in `rax`, values from `-4095` to `-1` are a negated [`Errno`]. `rcx` and `r11` are
+//! clobbered, all other registers are preserved.
+//!
+//! | Number | Name    | Arguments                  | Returns                 |
+//! |--------|---------|----------------------------|-------------------------|
+//! | 0      | `write` | `fd`, `buf`, `len`         | The bytes written       |
+//! | 1      | `exit`  | `code`                     | Never returns           |
+//! | 2      | `yield` |                            | 0                       |
+//! | 3      | `sleep` | `milliseconds`             | 0                       |
+//! | 4      | `mmap`  | `len`, `prot`              | The address of the area |
+//!
+//! `write` only supports standard output and standard error, which both go to the serial port.
+//! `mmap` returns zeroed memory, which is backed once it is first accessed. `prot` is a
+//! combination of [`PROT_READ`] and [`PROT_WRITE`].
+
+use x86_64::{instructions::interrupts, structures::paging::PageTableFlags};
+
+use crate::{
+    arch::usermode::SyscallFrame,
+    io::serial,
+    memory::vmm::{get_user_vmm, USER_END, USER_START},
+    paging::frame::FRAME_SIZE,
+    serial_println, task,
+};
+
+use self::user::user_slice;
+
+pub mod user;
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_MMAP: u64 = 4;
+
+/// The memory can be read.
+pub const PROT_READ: u64 = 1 << 0;
+/// The memory can be written.
+pub const PROT_WRITE: u64 = 1 << 1;
+
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// The amount of bytes `write` copies out of user memory at a time.
+const WRITE_CHUNK_SIZE: usize = 256;
+
+/// The error numbers returned by system calls, they match the ones used by Linux.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Errno {
+    EBADF = 9,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+/// The arguments of a system call, in order.
+struct SyscallArgs([u64; 6]);
+
+type SyscallResult = Result<u64, Errno>;
+
+/// The system calls, indexed by their number.
+const SYSCALL_TABLE: [fn(&SyscallArgs) -> SyscallResult; 5] = {
+    let mut table: [fn(&SyscallArgs) -> SyscallResult; 5] = [sys_unknown; 5];
+    table[SYS_WRITE as usize] = sys_write;
+    table[SYS_EXIT as usize] = sys_exit;
+    table[SYS_YIELD as usize] = sys_yield;
+    table[SYS_SLEEP as usize] = sys_sleep;
+    table[SYS_MMAP as usize] = sys_mmap;
+    table
+};
+
+/// Runs the system call described by the frame and stores its result in `rax`.
+pub fn dispatch(frame: &mut SyscallFrame) {
+    let args = SyscallArgs([
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ]);
+
+    // The entry stub masks interrupts, but system calls may take a while.
+    interrupts::enable();
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(syscall) => syscall(&args),
+        None => Err(Errno::ENOSYS),
+    };
+    interrupts::disable();
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (errno as u64).wrapping_neg(),
+    };
+}
+
+fn sys_unknown(_args: &SyscallArgs) -> SyscallResult {
+    Err(Errno::ENOSYS)
+}
+
+fn sys_write(args: &SyscallArgs) -> SyscallResult {
+    let [fd, buf, len, ..] = args.0;
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+
+    let buf = user_slice(buf, len)?;
+    // The bytes are copied before the serial port is locked, as reading user memory may fault.
+    for chunk in buf.chunks(WRITE_CHUNK_SIZE) {
+        let mut bytes = [0; WRITE_CHUNK_SIZE];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        serial::write_bytes(&bytes[..chunk.len()]);
+    }
+    Ok(len)
+}
+
+fn sys_exit(args: &SyscallArgs) -> SyscallResult {
+    serial_println!("User program exited with code {}", args.0[0] as i64);
+    task::exit()
+}
+
+fn sys_yield(_args: &SyscallArgs) -> SyscallResult {
+    task::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(args: &SyscallArgs) -> SyscallResult {
+    task::sleep_ms(args.0[0]);
+    Ok(0)
+}
+
+fn sys_mmap(args: &SyscallArgs) -> SyscallResult {
+    let [len, prot, ..] = args.0;
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    // Larger requests could never fit, and would overflow the size calculations.
+    if len > USER_END - USER_START {
+        return Err(Errno::ENOMEM);
+    }
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+
+    let region = get_user_vmm()
+        .reserve(len, FRAME_SIZE as u64, 0, flags)
+        .ok_or(Errno::ENOMEM)?;
+    Ok(region.start.as_u64())
+}
@@ -0,0 +1,61 @@
+use core::slice;
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use crate::{
+    memory::vmm::{get_user_vmm, USER_END, USER_START},
+    paging::{frame::FRAME_SIZE, mapper::effective_flags},
+};
+
+use super::Errno;
+
+const PAGE_SIZE: u64 = FRAME_SIZE as u64;
+
+/// Validates that user mode may access `len` bytes at `ptr`, writing to them if `write` is set.
+///
+/// Every page has to be either mapped user accessible in the caller's address space, or part of
+/// a lazily backed user region, which the page fault handler backs once the kernel touches it.
+pub fn validate_user_range(ptr: u64, len: u64, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = ptr.checked_add(len).ok_or(Errno::EFAULT)?;
+    if ptr < USER_START || end > USER_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let mut page = ptr & !(PAGE_SIZE - 1);
+    while page < end {
+        let addr = VirtAddr::new(page);
+        let allowed = match effective_flags(addr) {
+            Some(flags) => flags.contains(required),
+            None => matches!(
+                get_user_vmm().find_region(addr),
+                Some((region, info)) if region.contains(addr) && info.flags.contains(required)
+            ),
+        };
+        if !allowed {
+            return Err(Errno::EFAULT);
+        }
+        page += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+/// Returns the user buffer at `ptr` after validating it can be read.
+///
+/// The buffer may still be changed by user mode while the kernel reads it.
+pub fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], Errno> {
+    validate_user_range(ptr, len, false)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
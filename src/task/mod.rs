@@ -0,0 +1,224 @@
+//! Kernel threads.
+//!
+//! Every CPU runs the threads in a shared run queue, switching between them when a thread blocks,
+//! yields or exits, and whenever the local APIC timer ends its time slice. A CPU with nothing to
+//! run switches to an idle thread of its own.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+
+use alloc::{boxed::Box, sync::Arc};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{
+    arch::{context::init_thread_stack, cpu::cpu_info, fpu::FpuState, percpu::this_cpu},
+    memory::stack::KernelStack,
+};
+
+use self::scheduler::{add_sleeper, finish_switch, make_ready, reap, schedule};
+
+pub mod scheduler;
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        TaskId(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// Running on a CPU.
+    Running,
+    /// Waiting in the run queue.
+    Ready,
+    /// Waiting to be woken up with [`wake`].
+    Blocked,
+    /// Done, it is freed once no CPU runs on its stack anymore.
+    Exited,
+}
+
+type TaskEntry = Box<dyn FnOnce() + Send>;
+
+/// A kernel thread.
+pub struct Task {
+    id: TaskId,
+    state: AtomicU8,
+    /// Whether a CPU still runs on the stack of the task, it can not be resumed anywhere until
+    /// that CPU switched away.
+    on_cpu: AtomicBool,
+    /// The stack pointer saved by `switch_context` while the task is not running.
+    rsp: UnsafeCell<u64>,
+    fpu: UnsafeCell<FpuState>,
+    /// `None` for the threads that started on a boot stack.
+    stack: Option<KernelStack>,
+    /// Idle tasks are never queued, a CPU runs its own when there is nothing else to run.
+    idle: bool,
+}
+
+// Safe because `rsp` and `fpu` are only accessed by the CPU that switches to or away from the
+// task, with interrupts disabled.
+unsafe impl Send for Task {}
+unsafe impl Sync for Task {}
+
+impl Task {
+    fn new(entry: TaskEntry, idle: bool) -> Result<Arc<Task>, ()> {
+        let stack = KernelStack::new().ok_or(())?;
+        let entry = Box::into_raw(Box::new(entry));
+        let rsp = unsafe { init_thread_stack(stack.top(), entry as u64) };
+
+        Ok(Arc::new(Task {
+            id: TaskId::new(),
+            state: AtomicU8::new(TaskState::Ready as u8),
+            on_cpu: AtomicBool::new(false),
+            rsp: UnsafeCell::new(rsp),
+            fpu: UnsafeCell::new(FpuState::new()),
+            stack: Some(stack),
+            idle,
+        }))
+    }
+
+    /// Turns the code running on the current CPU into a task.
+    fn adopt_current() -> Arc<Task> {
+        Arc::new(Task {
+            id: TaskId::new(),
+            state: AtomicU8::new(TaskState::Running as u8),
+            on_cpu: AtomicBool::new(true),
+            rsp: UnsafeCell::new(0),
+            fpu: UnsafeCell::new(FpuState::new()),
+            stack: None,
+            idle: false,
+        })
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn state(&self) -> TaskState {
+        match self.state.load(Ordering::Acquire) {
+            0 => TaskState::Running,
+            1 => TaskState::Ready,
+            2 => TaskState::Blocked,
+            _ => TaskState::Exited,
+        }
+    }
+
+    fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Changes the state from `current` to `new`, returns false if it was not `current`.
+    fn transition(&self, current: TaskState, new: TaskState) -> bool {
+        self.state
+            .compare_exchange(
+                current as u8,
+                new as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    }
+}
+
+/// Starts a kernel thread running `entry`.
+pub fn spawn(entry: impl FnOnce() + Send + 'static) -> Result<TaskId, ()> {
+    // Freeing the stacks of exited threads is left to the ones that allocate new stacks.
+    reap();
+
+    let task = Task::new(Box::new(entry), false)?;
+    let id = task.id();
+    make_ready(task);
+    Ok(id)
+}
+
+/// Where new threads start, with interrupts disabled, see [`init_thread_stack`].
+pub extern "C" fn thread_start(entry: u64) -> ! {
+    finish_switch();
+    interrupts::enable();
+
+    let entry = unsafe { Box::from_raw(entry as *mut TaskEntry) };
+    entry();
+    exit()
+}
+
+/// Returns the thread running on the current CPU, or `None` if the CPU does not run the
+/// scheduler yet.
+pub fn current() -> Option<Arc<Task>> {
+    without_interrupts(|| unsafe { this_cpu().tasks().current().cloned() })
+}
+
+/// Lets other threads run before the current one continues.
+pub fn yield_now() {
+    without_interrupts(schedule);
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let current = current().expect("The scheduler is not running");
+    current.set_state(TaskState::Exited);
+    drop(current);
+
+    schedule();
+    unreachable!("An exited thread was resumed");
+}
+
+/// Blocks the current thread for at least `ms` milliseconds.
+///
+/// A deadline past the range of the TSC is clamped to its end, the thread then never wakes up.
+pub fn sleep_ms(ms: u64) {
+    let deadline = unsafe { _rdtsc() }.saturating_add(cpu_info().ms_to_tsc_ticks(ms));
+
+    let Some(current) = current() else {
+        // Without a scheduler there is nothing else to run.
+        while unsafe { _rdtsc() } < deadline {
+            core::hint::spin_loop()
+        }
+        return;
+    };
+
+    without_interrupts(|| {
+        current.set_state(TaskState::Blocked);
+        add_sleeper(deadline, current);
+        schedule();
+    });
+}
+
+/// Marks the current thread as blocked and returns it, so it can be handed to whatever wakes it
+/// up before it calls [`block`]. Returns `None` if the CPU does not run the scheduler yet.
+///
+/// The thread keeps running until it calls [`block`], a wakeup in between is not lost.
+pub fn prepare_to_block() -> Option<Arc<Task>> {
+    let current = current()?;
+    current.set_state(TaskState::Blocked);
+    Some(current)
+}
+
+/// Switches away from the current thread until it is woken up, if [`prepare_to_block`] was
+/// called and it was not woken up yet.
+pub fn block() {
+    without_interrupts(schedule);
+}
+
+/// Queues a blocked thread to run again, returns false if it was not blocked.
+pub fn wake(task: &Arc<Task>) -> bool {
+    if !task.transition(TaskState::Blocked, TaskState::Ready) {
+        return false;
+    }
+
+    make_ready(task.clone());
+    true
+}
@@ -0,0 +1,216 @@
+use core::{arch::x86_64::_rdtsc, hint::spin_loop, mem, sync::atomic::Ordering};
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec::Vec};
+use spin::Once;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{
+    arch::{
+        context::switch_context,
+        interrupt::{allocate_vector, register_irq_handler},
+        percpu::this_cpu,
+    },
+    sync::spinlock::IrqSpinLock,
+};
+
+use super::{Task, TaskState};
+
+/// How often the timer ends the time slice of the running thread, in Hz.
+const TIMER_FREQUENCY: u32 = 100;
+
+/// The threads waiting for a CPU.
+static RUN_QUEUE: IrqSpinLock<VecDeque<Arc<Task>>> = IrqSpinLock::new(VecDeque::new());
+
+/// The sleeping threads, with the TSC value they wake up at.
+static SLEEPING: IrqSpinLock<Vec<(u64, Arc<Task>)>> = IrqSpinLock::new(Vec::new());
+
+/// The exited threads, which are freed outside of the scheduler.
+static EXITED: IrqSpinLock<Vec<Arc<Task>>> = IrqSpinLock::new(Vec::new());
+
+/// The vector of the local APIC timer, it is the same on every CPU.
+static TIMER_VECTOR: Once<u8> = Once::new();
+
+/// The threads of a single CPU, see [`PerCpu::tasks`](crate::arch::percpu::PerCpu::tasks).
+pub struct CpuTasks {
+    current: Option<Arc<Task>>,
+    idle: Option<Arc<Task>>,
+    /// The thread the CPU just switched away from, until the switch is finished.
+    previous: Option<Arc<Task>>,
+}
+
+impl CpuTasks {
+    pub const fn new() -> CpuTasks {
+        CpuTasks {
+            current: None,
+            idle: None,
+            previous: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<&Arc<Task>> {
+        self.current.as_ref()
+    }
+}
+
+/// Starts scheduling on the current CPU, the code running turns into a thread.
+///
+/// The heap, the FPU and the local APIC have to be initialized.
+pub fn init_scheduler() {
+    let idle = Task::new(Box::new(idle_loop), true).expect("Could not create the idle thread");
+    let current = Task::adopt_current();
+
+    let vector = *TIMER_VECTOR.call_once(|| {
+        let vector = allocate_vector().expect("There is no vector left for the timer");
+        register_irq_handler(vector, timer_tick);
+        vector
+    });
+
+    without_interrupts(|| {
+        let cpu = this_cpu();
+        let tasks = unsafe { cpu.tasks() };
+        tasks.idle = Some(idle);
+        tasks.current = Some(current);
+
+        cpu.apic().start_timer(vector, TIMER_FREQUENCY);
+    });
+}
+
+fn idle_loop() {
+    loop {
+        reap();
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Ends the time slice of the running thread.
+fn timer_tick() {
+    wake_sleepers();
+    this_cpu().request_reschedule();
+}
+
+/// Switches to another thread if the timer asked for it, called when an interrupt returns.
+pub fn preempt() {
+    let cpu = this_cpu();
+    // Threads are only switched when returning to thread context, and not while the interrupted
+    // thread pinned itself to the CPU.
+    if cpu.in_interrupt() || !cpu.preemptible() || !cpu.take_reschedule() {
+        return;
+    }
+    drop(cpu);
+
+    schedule();
+}
+
+/// Queues a thread to run.
+pub(super) fn make_ready(task: Arc<Task>) {
+    RUN_QUEUE.lock().push_back(task);
+}
+
+/// Registers a blocked thread to be woken up once the TSC reaches `deadline`.
+pub(super) fn add_sleeper(deadline: u64, task: Arc<Task>) {
+    SLEEPING.lock().push((deadline, task));
+}
+
+fn wake_sleepers() {
+    let now = unsafe { _rdtsc() };
+
+    let mut sleeping = SLEEPING.lock();
+    let mut index = 0;
+    while index < sleeping.len() {
+        if sleeping[index].0 <= now {
+            let (_, task) = sleeping.swap_remove(index);
+            super::wake(&task);
+        } else {
+            index += 1;
+        }
+    }
+}
+
+/// Frees the threads that exited.
+pub(super) fn reap() {
+    let exited = mem::take(&mut *EXITED.lock());
+    drop(exited);
+}
+
+/// Switches to the next thread in the run queue.
+///
+/// The current thread keeps running if it is still runnable and nothing else is. Otherwise the
+/// CPU switches to its idle thread. Interrupts have to be disabled.
+pub(super) fn schedule() {
+    let cpu = this_cpu();
+    debug_assert!(
+        cpu.preemptible(),
+        "Switching threads while holding a spinlock"
+    );
+    let tasks = unsafe { cpu.tasks() };
+    let Some(current) = tasks.current.clone() else {
+        return;
+    };
+
+    let runnable = current.state() == TaskState::Running;
+    let next = match RUN_QUEUE.lock().pop_front() {
+        Some(next) => next,
+        None if runnable => return,
+        None => tasks.idle.clone().unwrap(),
+    };
+
+    // A thread that blocked might have been woken up, and queued, before it switched away.
+    if Arc::ptr_eq(&next, &current) {
+        current.set_state(TaskState::Running);
+        return;
+    }
+
+    // The thread may still be switching away on the CPU that ran it last.
+    while next.on_cpu.load(Ordering::Acquire) {
+        spin_loop();
+    }
+    next.on_cpu.store(true, Ordering::Relaxed);
+    next.set_state(TaskState::Running);
+
+    if let Some(stack) = &next.stack {
+        cpu.set_kernel_stack(stack.top());
+    }
+
+    unsafe { (*current.fpu.get()).save() };
+
+    let old_rsp = current.rsp.get();
+    let new_rsp = unsafe { *next.rsp.get() };
+    tasks.previous = Some(current);
+    tasks.current = Some(next);
+    drop(cpu);
+
+    unsafe { switch_context(old_rsp, new_rsp) };
+
+    finish_switch();
+}
+
+/// Completes a switch on the thread that was switched to, this can only happen once no CPU runs
+/// on the stack of the previous thread anymore.
+pub(super) fn finish_switch() {
+    let cpu = this_cpu();
+    let tasks = unsafe { cpu.tasks() };
+
+    let current = tasks.current.as_ref().unwrap();
+    unsafe { (*current.fpu.get()).restore() };
+
+    let Some(previous) = tasks.previous.take() else {
+        return;
+    };
+
+    match previous.state() {
+        // Preempted or yielded.
+        TaskState::Running => {
+            previous.set_state(TaskState::Ready);
+            if !previous.idle {
+                RUN_QUEUE.lock().push_back(previous.clone());
+            }
+        }
+        TaskState::Exited => EXITED.lock().push(previous.clone()),
+        // Blocked threads are queued again by whoever wakes them.
+        TaskState::Ready | TaskState::Blocked => {}
+    }
+
+    // Another CPU may pick the thread up and change its state as soon as this is cleared, so it
+    // has to come last.
+    previous.on_cpu.store(false, Ordering::Release);
+}
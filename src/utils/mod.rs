@@ -1,8 +1,6 @@
 use core::arch::x86_64::_rdtsc;
 
-// TODO: actually calculate this.
-/// CPU frequency in hz.
-pub const CPU_FREQUENCY: u64 = 3_000_000_000;
+use crate::arch::cpu::cpu_info;
 
 /// Sleeps for the amount of provided cycles.
 #[inline]
@@ -16,6 +14,6 @@ pub unsafe fn sleep_cycles(cycles: u64) {
 /// Sleeps for the amount of provided ms.
 #[inline]
 pub unsafe fn sleep_ms(ms: u64) {
-    let cycles = (ms * CPU_FREQUENCY) / 1000;
+    let cycles = cpu_info().ms_to_tsc_ticks(ms);
     sleep_cycles(cycles)
 }